@@ -0,0 +1,349 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use rand;
+
+use chess;
+use data::*;
+use engine::{Engine, PlecoEngine};
+use game::{self, GameState};
+
+
+/// How far ahead the AI looks when weighing up anything but a move.
+const PLAN_DEPTH: u16 = 2;
+
+
+/// Runs the AI off the render thread, one turn at a time.
+///
+/// The game loop calls `start` when the AI is to move and then `poll`s each
+/// frame until an action comes back. A search that is no longer wanted - the
+/// game was restarted or loaded, say - is abandoned with `cancel`, and its
+/// result is thrown away even if it does turn up.
+///
+/// Moves come from the bundled engine unless the controller is given another.
+/// Deeper searches are only started while the difficulty's `think_time` lasts,
+/// so how deep it gets depends on the machine.
+pub struct AiController {
+    engine: Arc<Mutex<Box<Engine>>>,
+    job: Option<AiJob>,
+}
+
+struct AiJob {
+    fen: String,
+    result: Receiver<Action>,
+    cancelled: Arc<AtomicBool>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl AiController {
+    pub fn new() -> Self {
+        AiController::with_engine(Box::new(PlecoEngine))
+    }
+
+    pub fn with_engine(engine: Box<Engine>) -> Self {
+        AiController {
+            engine: Arc::new(Mutex::new(engine)),
+            job: None,
+        }
+    }
+
+    pub fn is_thinking(&self) -> bool {
+        self.job.is_some()
+    }
+
+    /// The search depth currently being worked on, while thinking.
+    pub fn depth(&self) -> Option<usize> {
+        self.job
+            .as_ref()
+            .map(|job| job.depth.load(Ordering::Relaxed))
+    }
+
+    pub fn start(&mut self, state: &GameState) {
+        self.cancel();
+
+        let (sender, result) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let depth = Arc::new(AtomicUsize::new(0));
+
+        let state = state.clone();
+        let engine = self.engine.clone();
+        let job = AiJob {
+            fen: state.fen(),
+            result,
+            cancelled: cancelled.clone(),
+            depth: depth.clone(),
+        };
+
+        thread::spawn(move || {
+            // Always finish the first depth, so there's a move to make
+            let think_time = state.difficulty.think_time;
+            let mut search_started = None;
+            let keep_going = |searching: u16| {
+                depth.store(searching as usize, Ordering::Relaxed);
+                if searching == 1 {
+                    search_started = Some(Instant::now());
+                }
+
+                let in_time = match search_started {
+                    Some(started) if searching > 1 => {
+                        ::elapsed_time(started) < think_time
+                    }
+                    _ => true,
+                };
+                in_time && !cancelled.load(Ordering::Relaxed)
+            };
+
+            // A cancelled search may still be finishing with the engine
+            let mut engine = match engine.lock() {
+                Ok(engine) => engine,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+
+            if let Some(action) = plan_action(&state, &mut **engine, keep_going) {
+                // Nobody is listening if the job was cancelled.
+                let _ = sender.send(action);
+            }
+        });
+
+        self.job = Some(job);
+    }
+
+    /// Returns the AI's action once it has decided, provided the game is still
+    /// in the position it was asked about.
+    pub fn poll(&mut self, state: &GameState) -> Option<Action> {
+        let outcome = match self.job {
+            Some(ref job) => job.result.try_recv(),
+            None => return None,
+        };
+
+        match outcome {
+            Ok(action) => {
+                let job = self.job.take().unwrap();
+
+                if job.fen == state.fen() {
+                    Some(action)
+                }
+                else {
+                    None
+                }
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.job = None;
+                None
+            }
+        }
+    }
+
+    pub fn cancel(&mut self) {
+        if let Some(job) = self.job.take() {
+            job.cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for AiController {
+    fn default() -> Self {
+        AiController::new()
+    }
+}
+
+impl Drop for AiController {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+
+/// The AI's action, worked out on this thread. `None` if there's nothing it
+/// can do. There's no time limit: it always searches to the difficulty's full
+/// depth, so the same game and seed always get the same action.
+pub fn decide_action(state: &GameState) -> Option<Action> {
+    plan_action(state, &mut PlecoEngine, |_| true)
+}
+
+/// Whether the AI playing `color` accepts the offer the other side has made.
+///
+/// Take-backs are always granted. A draw is accepted unless the AI reckons
+/// it's ahead: the position is scored with the same shallow search used for
+/// planning, and the difference in coins is added in at the difficulty's
+/// `coin_value`.
+pub fn accepts_offer(state: &GameState, color: ChessColor) -> bool {
+    const DRAW_MARGIN: i32 = 50;
+
+    let offer = match state.offer {
+        Some(pending) if pending.from == game::opponent(color) => pending.offer,
+        _ => return false,
+    };
+
+    match offer {
+        Offer::TakeBack => true,
+        Offer::Draw => {
+            let depth = state.difficulty.depth.min(PLAN_DEPTH);
+            let mut score = chess::evaluate(state.board.pleco(), depth);
+            if state.whos_turn != color {
+                score = -score;
+            }
+
+            let coins = state.coins(color) as i32
+                - state.coins(game::opponent(color)) as i32;
+            score + coins * state.difficulty.coin_value as i32 < DRAW_MARGIN
+        }
+    }
+}
+
+/// Decides the AI's next action by weighing every sale and purchase against
+/// just getting on with a move.
+///
+/// Each shop action is tried out on a copy of the game, and the position it
+/// leaves is scored with a shallow engine search - so selling a pawn that
+/// shields the king shows up as the loss it is. Coins gained or spent are
+/// added in at the difficulty's `coin_value`. A shop action is only taken if
+/// it beats moving straight away by a clear margin; otherwise the engine
+/// picks a move as usual.
+///
+/// `keep_going` is asked before each stage of thinking, with the search
+/// depth about to start (zero while weighing up the shop), and can call the
+/// whole thing off. If `engine` fails, the bundled one stands in for it.
+fn plan_action<F>(
+    state: &GameState,
+    engine: &mut Engine,
+    mut keep_going: F,
+) -> Option<Action>
+where
+    F: FnMut(u16) -> bool,
+{
+    use rand::Rng;
+
+    const SHOP_MARGIN: i32 = 25;
+
+    let difficulty = &state.difficulty;
+
+    // Rolling on a fork keeps the AI repeatable for a given seed, without
+    // touching the draws the shop restocks from
+    let mut rng = state.rng.fork(state.history.len() as u64);
+
+    if !keep_going(0) {
+        return None;
+    }
+
+    let depth = difficulty.depth.min(PLAN_DEPTH);
+    let coin_value = difficulty.coin_value as i32;
+    let wants_to_buy = rng.gen::<f32>() < difficulty.buy_chance;
+
+    let mut best_shop_action = None;
+    let mut best_score = chess::evaluate(state.board.pleco(), depth) + SHOP_MARGIN;
+
+    for (action, coins) in shop_actions(state, wants_to_buy, depth) {
+        let score = match score_after(state, action, depth) {
+            Some(score) => score + coins * coin_value,
+            None => continue,
+        };
+
+        if score > best_score {
+            best_score = score;
+            best_shop_action = Some(action);
+        }
+    }
+
+    if best_shop_action.is_some() {
+        return best_shop_action;
+    }
+
+    let board = state.board.pleco();
+    let mov = match engine.search(board, difficulty.depth, &mut keep_going) {
+        Ok(mov) => mov,
+        Err(err) => {
+            eprintln!("Engine failed, falling back to pleco: {}", err);
+            chess::search_move(board, difficulty.depth, &mut keep_going)
+        }
+    }?;
+
+    if rng.gen::<f32>() < difficulty.blunder_chance {
+        let moves = state
+            .board
+            .pleco()
+            .generate_moves()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !moves.is_empty() {
+            let blunder = rand::seq::sample_slice(&mut rng, &moves, 1)[0];
+            return Some(chess::move_action(blunder));
+        }
+    }
+
+    Some(chess::move_action(mov))
+}
+
+/// Every sale, and every affordable purchase if `buying`, along with the
+/// coins each would gain (or cost, as a negative number). Purchases go on the
+/// square that looks best `depth` plies ahead.
+fn shop_actions(
+    state: &GameState,
+    buying: bool,
+    depth: u16,
+) -> Vec<(Action, i32)> {
+    let whos_turn = state.whos_turn;
+    let mut actions = Vec::new();
+
+    for piece in &state.pieces {
+        if piece.color == whos_turn && piece.piece_type != PieceType::King {
+            let refund =
+                chess::sell_price(&state.prices, piece.piece_type, piece.moved);
+            actions.push((Action::Sell(piece.position), refund as i32));
+        }
+    }
+
+    if buying {
+        let coins = state.coins(whos_turn);
+
+        for (slot, piece_for_sale) in state.pieces_for_sale.iter().enumerate() {
+            let piece_for_sale = match *piece_for_sale {
+                Some(piece_for_sale) => piece_for_sale,
+                None => continue,
+            };
+
+            let price = chess::buy_price(&state.prices, piece_for_sale);
+            if price > coins {
+                continue;
+            }
+
+            if let Some(place) = state.suggested_placement(slot, depth) {
+                actions.push((Action::Buy { slot, place }, -(price as i32)));
+            }
+        }
+    }
+
+    actions
+}
+
+/// Scores the position a shop action leaves, for the player who took it -
+/// even if the rules made it the other side's turn. Actions that leave the
+/// player still to move but with no legal move are never worth taking.
+fn score_after(state: &GameState, action: Action, depth: u16) -> Option<i32> {
+    let mut after = state.clone();
+    let result = after.apply(action);
+
+    if result.is_empty() {
+        return None;
+    }
+
+    let stuck = after.whos_turn == state.whos_turn
+        && after.board.pleco().generate_moves().is_empty();
+    if stuck {
+        return None;
+    }
+
+    let score = chess::evaluate(after.board.pleco(), depth);
+    if after.whos_turn == state.whos_turn {
+        Some(score)
+    }
+    else {
+        Some(-score)
+    }
+}
@@ -0,0 +1,215 @@
+//! The Purchess AI as a standalone engine, for tournament harnesses.
+//!
+//! It reads one command per line on stdin and answers on stdout, in the style
+//! of UCI. The chess commands work as they do there:
+//!
+//! - `uci` - replies with `id name` and `uciok`.
+//! - `isready` - replies `readyok`.
+//! - `ucinewgame` - starts over from the starting position of the rules.
+//! - `position startpos [moves ...]` or `position fen <fen> [moves ...]` -
+//!   sets up a position, then plays any actions that follow `moves`. Actions
+//!   are written as in the engine's replies, below.
+//! - `setoption name Difficulty value <name>` - one of the difficulty presets
+//!   from the config.
+//! - `setoption name Rules value <name>` - one of the rule presets from the
+//!   config. Takes effect from the next `ucinewgame` or `position`.
+//! - `go [depth <n>]` - thinks, then replies `bestmove <action>`, or
+//!   `bestmove (none)` if there's nothing to do. Depths below 1 count as 1.
+//! - `quit`
+//!
+//! The economy has its own commands. Both sides start with the rules'
+//! starting coins, and a new position gets a randomly stocked shop, so send
+//! these after `position`:
+//!
+//! - `wallet <white coins> <black coins>`
+//! - `shop <slot> <slot> <slot>` - each slot is a piece letter, followed by
+//!   `*` if it's discounted, or `-` if the slot is empty.
+//! - `d` - describes the current position, wallets and shop.
+//!
+//! `bestmove` is followed by a move in long algebraic notation (`e2e4`,
+//! `e7e8q`), `sell <square>`, or `buy <slot> <square>` with slots counted
+//! from zero. Shop actions don't end the turn, so after one the harness
+//! should play it and `go` again. Problems are reported as `info string`.
+
+extern crate chessjam;
+
+use std::io::{self, BufRead, Write};
+
+use chessjam::{
+    ai,
+    chess,
+    config::{self, Config},
+    data::*,
+    game::{self, GameState},
+    pgn,
+    rng::GameRng,
+};
+
+
+fn main() {
+    let config = config::load_config();
+    let mut state = GameState::new(&config);
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let reply = match words.first() {
+            Some(&"quit") => break,
+            Some(&command) => {
+                run_command(command, &words[1..], &mut state, &config)
+            }
+            None => continue,
+        };
+
+        let reply =
+            reply.unwrap_or_else(|err| vec![format!("info string {}", err)]);
+        for line in reply {
+            writeln!(out, "{}", line).unwrap();
+        }
+        out.flush().unwrap();
+    }
+}
+
+
+fn run_command(
+    command: &str,
+    args: &[&str],
+    state: &mut GameState,
+    config: &Config,
+) -> Result<Vec<String>, String> {
+    match command {
+        "uci" => Ok(vec!["id name Purchess".to_owned(), "uciok".to_owned()]),
+        "isready" => Ok(vec!["readyok".to_owned()]),
+        "ucinewgame" => {
+            let difficulty = state.difficulty.clone();
+            let seed = GameRng::random().seed();
+            *state = GameState::with_rules(config, state.rules.clone(), seed);
+            state.difficulty = difficulty;
+            Ok(vec![])
+        }
+        "position" => {
+            let difficulty = state.difficulty.clone();
+            *state = set_position(args, state.rules.clone(), config)?;
+            state.difficulty = difficulty;
+            Ok(vec![])
+        }
+        "setoption" => {
+            match args {
+                ["name", "Difficulty", "value", name] => {
+                    state.difficulty = game::difficulty(config, name)
+                        .ok_or_else(|| format!("Unknown difficulty {}", name))?;
+                }
+                ["name", "Rules", "value", name] => {
+                    state.rules = game::rule_set(config, name)?;
+                }
+                _ => return Err(format!("Unknown option {}", args.join(" "))),
+            }
+            Ok(vec![])
+        }
+        "wallet" => {
+            match args {
+                [white, black] => {
+                    state.white_coins = parse_coins(white)?;
+                    state.black_coins = parse_coins(black)?;
+                }
+                _ => return Err("Expected wallet <white> <black>".to_owned()),
+            }
+            Ok(vec![])
+        }
+        "shop" => {
+            match pgn::parse_shop(&args.join(" ")) {
+                Some(ref shop) if shop.len() == game::SHOP_SIZE => {
+                    state.set_shop(shop);
+                    Ok(vec![])
+                }
+                _ => Err(format!("Expected {} shop slots", game::SHOP_SIZE)),
+            }
+        }
+        "d" => Ok(vec![
+            format!("info string fen {}", state.fen()),
+            format!(
+                "info string wallet {} {}",
+                state.white_coins, state.black_coins
+            ),
+            format!(
+                "info string shop {}",
+                pgn::format_shop(&state.pieces_for_sale)
+            ),
+        ]),
+        "go" => {
+            if state.game_outcome != GameOutcome::Ongoing {
+                return Ok(vec!["bestmove (none)".to_owned()]);
+            }
+
+            let mut thinking = state.clone();
+            if let ["depth", depth] = args {
+                thinking.difficulty.depth = depth
+                    .parse::<u16>()
+                    .map_err(|_| format!("Bad depth {}", depth))?
+                    .max(1);
+            }
+
+            let action = match ai::decide_action(&thinking) {
+                Some(action) => chess::format_action(action),
+                None => "(none)".to_owned(),
+            };
+            Ok(vec![format!("bestmove {}", action)])
+        }
+        _ => Err(format!("Unknown command {}", command)),
+    }
+}
+
+fn set_position(
+    args: &[&str],
+    rules: RuleSet,
+    config: &Config,
+) -> Result<GameState, String> {
+    let moves_start = args
+        .iter()
+        .position(|&word| word == "moves")
+        .unwrap_or_else(|| args.len());
+
+    let seed = GameRng::random().seed();
+    let mut state = match args.first() {
+        Some(&"startpos") => GameState::with_rules(config, rules, seed),
+        Some(&"fen") => {
+            let fen = args[1..moves_start].join(" ");
+            GameState::from_fen_with_rules(config, &fen, rules, seed)?
+        }
+        _ => return Err("Expected position startpos or position fen".to_owned()),
+    };
+
+    let mut words = args[moves_start..].iter().skip(1);
+    while let Some(&word) = words.next() {
+        let text = match word {
+            "sell" => format!("sell {}", words.next().unwrap_or(&"")),
+            "buy" => {
+                let slot = words.next().unwrap_or(&"");
+                format!("buy {} {}", slot, words.next().unwrap_or(&""))
+            }
+            mov => mov.to_owned(),
+        };
+
+        let action = chess::parse_action(&text)
+            .ok_or_else(|| format!("Bad action {}", text))?;
+        let result = state.apply(action);
+
+        if result.is_empty() {
+            return Err(format!("Illegal action {}", text));
+        }
+    }
+
+    Ok(state)
+}
+
+fn parse_coins(text: &str) -> Result<u32, String> {
+    text.parse().map_err(|_| format!("Bad coin count {}", text))
+}
@@ -0,0 +1,452 @@
+//! Plays AI-vs-AI games of Purchess without a window, for balancing the piece
+//! prices and shop weights.
+//!
+//! Options:
+//!
+//! - `--games <n>` - how many games to play (100 by default).
+//! - `--seed <n>` - game `i` is seeded with `n + i`, so any game in the run
+//!   can be played again alone with `--games 1 --seed <its seed>`. Picked at
+//!   random if not given. Seeds go up to `i64::MAX`, so they can be saved.
+//! - `--difficulty <name>` - the preset both sides play at (the config's
+//!   `ai.difficulty` by default).
+//! - `--rules <name>` - the rule preset to play under (the config's
+//!   `game.rules` by default).
+//! - `--max-plies <n>` - games still going after this many actions are
+//!   abandoned and counted as unfinished (500 by default).
+//! - `--csv <path>`, `--json <path>` - write one record per game to a file.
+//!
+//! A summary table is always printed once all the games are done.
+
+extern crate chessjam;
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::process;
+
+use chessjam::{
+    ai,
+    chess,
+    config::{self, Config},
+    data::*,
+    game::{self, GameState},
+    pgn,
+    rng::{self, GameRng},
+};
+
+
+struct Options {
+    games: usize,
+    seed: u64,
+    difficulty: Option<String>,
+    rules: Option<String>,
+    max_plies: usize,
+    csv_path: Option<String>,
+    json_path: Option<String>,
+}
+
+/// What happened in one game. Per-side figures are indexed white then black.
+struct GameRecord {
+    seed: u64,
+    outcome: GameOutcome,
+    plies: usize,
+    earned: [u32; 2],
+    spent: [u32; 2],
+    bought: [[u32; 5]; 2],
+}
+
+
+fn main() {
+    let options = match options_from_args() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let config = config::load_config();
+    let difficulty = options
+        .difficulty
+        .clone()
+        .unwrap_or_else(|| config.ai.difficulty.to_string());
+
+    let difficulty = match game::difficulty(&config, &difficulty) {
+        Some(difficulty) => difficulty,
+        None => {
+            eprintln!("Unknown difficulty {}", difficulty);
+            process::exit(1);
+        }
+    };
+
+    let rules = match options.rules {
+        Some(ref name) => match game::rule_set(&config, name) {
+            Ok(rules) => rules,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        },
+        None => game::rules(&config),
+    };
+
+    let mut records = Vec::with_capacity(options.games);
+    for index in 0..options.games {
+        let seed = options.seed + index as u64;
+        let record =
+            play_game(&config, &difficulty, &rules, seed, options.max_plies);
+
+        eprintln!(
+            "Game {}/{} (seed {}): {} in {} plies",
+            index + 1,
+            options.games,
+            seed,
+            outcome_name(record.outcome),
+            record.plies
+        );
+        records.push(record);
+    }
+
+    if let Some(ref path) = options.csv_path {
+        if let Err(err) = write_file(path, &csv(&records)) {
+            eprintln!("Couldn't write {}: {}", path, err);
+        }
+    }
+
+    if let Some(ref path) = options.json_path {
+        if let Err(err) = write_file(path, &json(&records)) {
+            eprintln!("Couldn't write {}: {}", path, err);
+        }
+    }
+
+    print!("{}", summary(&records, &options, &difficulty, &rules));
+}
+
+
+fn options_from_args() -> Result<Options, String> {
+    let mut options = Options {
+        games: 100,
+        seed: GameRng::random().seed(),
+        difficulty: None,
+        rules: None,
+        max_plies: 500,
+        csv_path: None,
+        json_path: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--games" => options.games = parse_number(&arg, args.next())?,
+            "--seed" => {
+                let value = args.next().unwrap_or_default();
+                options.seed = rng::parse_seed(&value)
+                    .ok_or_else(|| format!("Bad value {} for {}", value, arg))?;
+            }
+            "--difficulty" => options.difficulty = args.next(),
+            "--rules" => options.rules = args.next(),
+            "--max-plies" => options.max_plies = parse_number(&arg, args.next())?,
+            "--csv" => options.csv_path = args.next(),
+            "--json" => options.json_path = args.next(),
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+
+    if options.seed > rng::MAX_SEED.saturating_sub(options.games as u64) {
+        return Err(format!(
+            "Seeds would run past {} with {} games",
+            rng::MAX_SEED,
+            options.games
+        ));
+    }
+
+    Ok(options)
+}
+
+fn parse_number<T: std::str::FromStr>(
+    option: &str,
+    value: Option<String>,
+) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    value
+        .parse()
+        .map_err(|_| format!("Bad value {} for {}", value, option))
+}
+
+
+fn play_game(
+    config: &Config,
+    difficulty: &Difficulty,
+    rules: &RuleSet,
+    seed: u64,
+    max_plies: usize,
+) -> GameRecord {
+    let mut state = GameState::with_rules(config, rules.clone(), seed);
+    state.players = Players {
+        white: PlayerKind::Ai,
+        black: PlayerKind::Ai,
+    };
+    state.difficulty = difficulty.clone();
+
+    while state.game_outcome == GameOutcome::Ongoing
+        && state.history.len() < max_plies
+    {
+        let action = match ai::decide_action(&state) {
+            Some(action) => action,
+            None => {
+                eprintln!("Seed {}: AI found nothing to do", seed);
+                break;
+            }
+        };
+
+        if state.apply(action).is_empty() {
+            eprintln!(
+                "Seed {}: AI chose illegal action {}",
+                seed,
+                chess::format_action(action)
+            );
+            break;
+        }
+    }
+
+    let mut record = GameRecord {
+        seed,
+        outcome: state.game_outcome,
+        plies: state.history.len(),
+        earned: [0; 2],
+        spent: [0; 2],
+        bought: [[0; 5]; 2],
+    };
+
+    for entry in &state.history {
+        let side = side_index(entry.color);
+        let capture_payee = match rules.capture_pays {
+            CapturePays::Victim => 1 - side,
+            CapturePays::Capturer => side,
+        };
+
+        match entry.action {
+            Action::Move { .. } => record.earned[capture_payee] += entry.coins,
+            Action::Sell(_) => record.earned[side] += entry.coins,
+            Action::Buy { .. } => {
+                record.spent[side] += entry.coins;
+                if let Some(kind) = purchasable_index(entry.piece_type) {
+                    record.bought[side][kind] += 1;
+                }
+            }
+            Action::Resign(_) | Action::Offer(..) | Action::Answer(..) => (),
+        }
+    }
+
+    record
+}
+
+fn side_index(color: ChessColor) -> usize {
+    match color {
+        ChessColor::White => 0,
+        ChessColor::Black => 1,
+    }
+}
+
+fn purchasable_index(piece_type: PieceType) -> Option<usize> {
+    chess::PURCHASABLE
+        .iter()
+        .position(|&purchasable| purchasable == piece_type)
+}
+
+fn outcome_name(outcome: GameOutcome) -> &'static str {
+    match outcome {
+        GameOutcome::Ongoing => "unfinished",
+        GameOutcome::Victory(ChessColor::White) => "white wins",
+        GameOutcome::Victory(ChessColor::Black) => "black wins",
+        GameOutcome::Stalemate => "stalemate",
+        GameOutcome::Draw(DrawReason::Repetition) => "repetition",
+        GameOutcome::Draw(DrawReason::FiftyMoves) => "fifty moves",
+        GameOutcome::Draw(DrawReason::InsufficientMaterial) => {
+            "insufficient material"
+        }
+        GameOutcome::OutOfTime(ChessColor::White) => "white out of time",
+        GameOutcome::OutOfTime(ChessColor::Black) => "black out of time",
+        GameOutcome::Resigned(ChessColor::White) => "white resigned",
+        GameOutcome::Resigned(ChessColor::Black) => "black resigned",
+        GameOutcome::AgreedDraw => "agreed draw",
+    }
+}
+
+fn piece_name(piece_type: PieceType) -> String {
+    format!("{:?}", piece_type).to_lowercase()
+}
+
+
+fn summary(
+    records: &[GameRecord],
+    options: &Options,
+    difficulty: &Difficulty,
+    rules: &RuleSet,
+) -> String {
+    let games = records.len().max(1) as f32;
+    let (mut white_wins, mut black_wins, mut draws, mut unfinished) =
+        (0, 0, 0, 0);
+
+    for record in records {
+        match (record.outcome, record.outcome.winner()) {
+            (_, Some(ChessColor::White)) => white_wins += 1,
+            (_, Some(ChessColor::Black)) => black_wins += 1,
+            (GameOutcome::Ongoing, None) => unfinished += 1,
+            (_, None) => draws += 1,
+        }
+    }
+
+    let mut lines = vec![
+        format!(
+            "{} games at {} with {} rules, seeds {} onwards",
+            records.len(),
+            difficulty.name,
+            rules.name,
+            options.seed
+        ),
+        String::new(),
+    ];
+
+    for &(label, value) in &[
+        ("White wins", white_wins),
+        ("Black wins", black_wins),
+        ("Draws", draws),
+        ("Unfinished", unfinished),
+    ] {
+        lines.push(format!(
+            "{:<16}{:>8}{:>9.1}%",
+            label,
+            value,
+            value as f32 * 100.0 / games
+        ));
+    }
+
+    lines.push(format!(
+        "{:<16}{:>8.1}",
+        "Average plies",
+        average(records, |record| record.plies as u32)
+    ));
+    lines.push(String::new());
+    lines.push(format!("{:<16}{:>8}{:>8}", "Per game", "White", "Black"));
+
+    let mut rows = vec![
+        (
+            "Coins earned".to_owned(),
+            average(records, |record| record.earned[0]),
+            average(records, |record| record.earned[1]),
+        ),
+        (
+            "Coins spent".to_owned(),
+            average(records, |record| record.spent[0]),
+            average(records, |record| record.spent[1]),
+        ),
+    ];
+
+    for (kind, &piece_type) in chess::PURCHASABLE.iter().enumerate() {
+        rows.push((
+            format!("{}s bought", piece_name(piece_type)),
+            average(records, |record| record.bought[0][kind]),
+            average(records, |record| record.bought[1][kind]),
+        ));
+    }
+
+    for (label, white, black) in rows {
+        lines.push(format!("{:<16}{:>8.2}{:>8.2}", label, white, black));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+fn average<F>(records: &[GameRecord], value: F) -> f32
+where
+    F: Fn(&GameRecord) -> u32,
+{
+    let total = records.iter().map(value).sum::<u32>();
+    total as f32 / records.len().max(1) as f32
+}
+
+fn csv(records: &[GameRecord]) -> String {
+    let mut header = vec![
+        "seed".to_owned(),
+        "result".to_owned(),
+        "outcome".to_owned(),
+        "plies".to_owned(),
+    ];
+
+    for side in &["white", "black"] {
+        header.push(format!("{}_earned", side));
+        header.push(format!("{}_spent", side));
+
+        for &piece_type in &chess::PURCHASABLE {
+            header.push(format!("{}_{}s_bought", side, piece_name(piece_type)));
+        }
+    }
+
+    let mut lines = vec![header.join(",")];
+
+    for record in records {
+        let mut fields = vec![
+            record.seed.to_string(),
+            pgn::result_text(record.outcome).to_owned(),
+            outcome_name(record.outcome).to_owned(),
+            record.plies.to_string(),
+        ];
+
+        for side in 0..2 {
+            fields.push(record.earned[side].to_string());
+            fields.push(record.spent[side].to_string());
+            fields.extend(record.bought[side].iter().map(|n| n.to_string()));
+        }
+
+        lines.push(fields.join(","));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+fn json(records: &[GameRecord]) -> String {
+    let games = records
+        .iter()
+        .map(|record| {
+            let sides = ["white", "black"]
+                .iter()
+                .enumerate()
+                .map(|(side, name)| {
+                    let bought = chess::PURCHASABLE
+                        .iter()
+                        .zip(&record.bought[side])
+                        .map(|(&piece_type, count)| {
+                            format!("\"{}\": {}", piece_name(piece_type), count)
+                        })
+                        .collect::<Vec<_>>();
+
+                    format!(
+                        "\"{}\": {{\"earned\": {}, \"spent\": {}, \
+                         \"bought\": {{{}}}}}",
+                        name,
+                        record.earned[side],
+                        record.spent[side],
+                        bought.join(", ")
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            format!(
+                "  {{\"seed\": {}, \"result\": \"{}\", \"outcome\": \"{}\", \
+                 \"plies\": {}, {}}}",
+                record.seed,
+                pgn::result_text(record.outcome),
+                outcome_name(record.outcome),
+                record.plies,
+                sides.join(", ")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!("[\n{}\n]\n", games.join(",\n"))
+}
+
+fn write_file(path: &str, contents: &str) -> io::Result<()> {
+    File::create(path)?.write_all(contents.as_bytes())
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+
+use pleco::{BitMove, Board};
+
+
+/// A pleco `Board` kept in step with the game's pieces.
+///
+/// Moves are applied to it incrementally. Shop actions can't be expressed as
+/// pleco moves, so they rebuild the board from a FEN instead - but the record
+/// of positions reached so far survives either way.
+pub struct SyncedBoard {
+    board: Board,
+    positions: Vec<String>,
+    repetition_start: usize,
+}
+
+impl SyncedBoard {
+    pub fn new(fen: &str) -> Result<Self, String> {
+        let board = parse_board(fen)?;
+        let positions = vec![position_key(&board.fen())];
+
+        Ok(SyncedBoard {
+            board,
+            positions,
+            repetition_start: 0,
+        })
+    }
+
+    /// Rebuilds a board from a saved position along with the positions that
+    /// led up to it.
+    pub fn with_history(
+        fen: &str,
+        positions: Vec<String>,
+        repetition_start: usize,
+    ) -> Result<Self, String> {
+        let mut board = SyncedBoard::new(fen)?;
+
+        if !positions.is_empty() && repetition_start < positions.len() {
+            board.positions = positions;
+            board.repetition_start = repetition_start;
+        }
+
+        Ok(board)
+    }
+
+    pub fn pleco(&self) -> &Board {
+        &self.board
+    }
+
+    pub fn positions(&self) -> &[String] {
+        &self.positions
+    }
+
+    pub fn repetition_start(&self) -> usize {
+        self.repetition_start
+    }
+
+    /// How many times the current position has occurred since the last shop
+    /// action, including this occurrence.
+    pub fn repetitions(&self) -> usize {
+        let recent = &self.positions[self.repetition_start..];
+
+        match recent.last() {
+            Some(current) => recent.iter().filter(|&key| key == current).count(),
+            None => 0,
+        }
+    }
+
+    pub fn apply_move(&mut self, mov: BitMove) {
+        self.board.apply_move(mov);
+        self.positions.push(position_key(&self.board.fen()));
+    }
+
+    /// Replaces the position with an edited one. The board is left untouched
+    /// if pleco can't play from the new position.
+    ///
+    /// Buying or selling changes the material on the board for good, so like
+    /// a capture it means no earlier position can be repeated.
+    pub fn edit(&mut self, fen: &str) -> Result<(), String> {
+        self.board = parse_board(fen)?;
+        self.repetition_start = self.positions.len();
+        self.positions.push(position_key(&self.board.fen()));
+        Ok(())
+    }
+}
+
+impl Clone for SyncedBoard {
+    fn clone(&self) -> Self {
+        SyncedBoard {
+            board: parse_board(&self.board.fen()).unwrap(),
+            positions: self.positions.clone(),
+            repetition_start: self.repetition_start,
+        }
+    }
+}
+
+impl fmt::Debug for SyncedBoard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SyncedBoard({})", self.board.fen())
+    }
+}
+
+
+/// The fields of a FEN that identify a position for repetition purposes:
+/// placement, side to move, castling rights and en passant square.
+pub fn position_key(fen: &str) -> String {
+    fen.split_whitespace().take(4).collect::<Vec<_>>().join(" ")
+}
+
+fn parse_board(fen: &str) -> Result<Board, String> {
+    Board::from_fen(fen).map_err(|err| format!("Unplayable position: {:?}", err))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chess;
+
+    const KNIGHTS: &str = "4k1n1/8/8/8/8/8/8/4K1N1 w - - 0 1";
+
+    fn play(board: &mut SyncedBoard, from: &str, to: &str) {
+        let from = chess::parse_square(from).unwrap();
+        let to = chess::parse_square(to).unwrap();
+        let mov = chess::find_move(board.pleco(), from, to, None).unwrap();
+        board.apply_move(mov);
+    }
+
+    fn shuffle_knights(board: &mut SyncedBoard) {
+        play(board, "g1", "f3");
+        play(board, "g8", "f6");
+        play(board, "f3", "g1");
+        play(board, "f6", "g8");
+    }
+
+    #[test]
+    fn shuffling_back_and_forth_repeats_threefold() {
+        let mut board = SyncedBoard::new(KNIGHTS).unwrap();
+        assert_eq!(board.repetitions(), 1);
+
+        shuffle_knights(&mut board);
+        assert_eq!(board.repetitions(), 2);
+
+        shuffle_knights(&mut board);
+        assert_eq!(board.repetitions(), 3);
+        assert_eq!(board.positions().len(), 9);
+    }
+
+    #[test]
+    fn edits_break_the_repetition_count() {
+        let mut board = SyncedBoard::new(KNIGHTS).unwrap();
+        shuffle_knights(&mut board);
+        assert_eq!(board.repetitions(), 2);
+
+        // Selling the knight and buying it back lands on the same position
+        let fen = board.pleco().fen();
+        board.edit(&fen).unwrap();
+        assert_eq!(board.repetition_start(), 5);
+        assert_eq!(board.repetitions(), 1);
+
+        shuffle_knights(&mut board);
+        assert_eq!(board.repetitions(), 2);
+    }
+
+    #[test]
+    fn unplayable_edits_leave_the_board_alone() {
+        let mut board = SyncedBoard::new(KNIGHTS).unwrap();
+        shuffle_knights(&mut board);
+
+        assert!(board.edit("not a position").is_err());
+        assert_eq!(board.repetition_start(), 0);
+        assert_eq!(board.repetitions(), 2);
+    }
+}
@@ -1,5 +1,6 @@
 use okmath::*;
 use pleco::{BitMove, Board};
+use rand::Rng;
 
 use data::*;
 
@@ -15,7 +16,266 @@ pub fn piece_at(position: Vec2<i32>, pieces: &[Piece]) -> Option<usize> {
 }
 
 
-pub fn generate_fen(pieces: &[Piece], whos_turn: ChessColor) -> String {
+pub fn square_name(position: Vec2<i32>) -> String {
+    let (x, y) = position.as_tuple();
+    format!("{}{}", (b'a' + x as u8) as char, y + 1)
+}
+
+pub fn parse_square(name: &str) -> Option<Vec2<i32>> {
+    let bytes = name.as_bytes();
+    if bytes.len() != 2 {
+        return None;
+    }
+
+    let position = vec2(bytes[0] as i32 - 'a' as i32, bytes[1] as i32 - '1' as i32);
+    if ::valid_square(position) {
+        Some(position)
+    }
+    else {
+        None
+    }
+}
+
+
+/// Actions in a compact text form: moves in UCI style (`e2e4`, `e7e8q`),
+/// `sell <square>`, and `buy <slot> <square>` with a zero-based shop slot.
+/// The rest name their side: `resign <color>`, `offer <color> draw|takeback`
+/// and `answer <color> accept|decline`.
+pub fn format_action(action: Action) -> String {
+    match action {
+        Action::Move {
+            from,
+            to,
+            promotion,
+        } => {
+            let mut text = format!("{}{}", square_name(from), square_name(to));
+            if let Some(piece_type) = promotion {
+                text.push(fen_char(ChessColor::Black, piece_type));
+            }
+            text
+        }
+        Action::Sell(position) => format!("sell {}", square_name(position)),
+        Action::Buy { slot, place } => {
+            format!("buy {} {}", slot, square_name(place))
+        }
+        Action::Resign(color) => format!("resign {}", color_name(color)),
+        Action::Offer(color, offer) => {
+            let offer = match offer {
+                Offer::Draw => "draw",
+                Offer::TakeBack => "takeback",
+            };
+            format!("offer {} {}", color_name(color), offer)
+        }
+        Action::Answer(color, accept) => {
+            let answer = if accept { "accept" } else { "decline" };
+            format!("answer {} {}", color_name(color), answer)
+        }
+    }
+}
+
+pub fn parse_action(text: &str) -> Option<Action> {
+    let mut words = text.split_whitespace();
+
+    match words.next()? {
+        "sell" => Some(Action::Sell(parse_square(words.next()?)?)),
+        "buy" => {
+            let slot = words.next()?.parse::<usize>().ok()?;
+            let place = parse_square(words.next()?)?;
+            Some(Action::Buy { slot, place })
+        }
+        "resign" => Some(Action::Resign(parse_color(words.next()?)?)),
+        "offer" => {
+            let color = parse_color(words.next()?)?;
+            let offer = match words.next()? {
+                "draw" => Offer::Draw,
+                "takeback" => Offer::TakeBack,
+                _ => return None,
+            };
+            Some(Action::Offer(color, offer))
+        }
+        "answer" => {
+            let color = parse_color(words.next()?)?;
+            let accept = match words.next()? {
+                "accept" => true,
+                "decline" => false,
+                _ => return None,
+            };
+            Some(Action::Answer(color, accept))
+        }
+        mov => {
+            if !mov.is_ascii() || mov.len() < 4 || mov.len() > 5 {
+                return None;
+            }
+
+            let from = parse_square(&mov[0..2])?;
+            let to = parse_square(&mov[2..4])?;
+            let promotion = match mov[4..].chars().next() {
+                Some(ch) => Some(piece_from_fen_char(ch)?.1),
+                None => None,
+            };
+
+            Some(Action::Move {
+                from,
+                to,
+                promotion,
+            })
+        }
+    }
+}
+
+pub fn color_name(color: ChessColor) -> &'static str {
+    match color {
+        ChessColor::White => "white",
+        ChessColor::Black => "black",
+    }
+}
+
+pub fn parse_color(name: &str) -> Option<ChessColor> {
+    match name {
+        "white" => Some(ChessColor::White),
+        "black" => Some(ChessColor::Black),
+        _ => None,
+    }
+}
+
+
+pub fn fen_char(color: ChessColor, piece_type: PieceType) -> char {
+    use data::ChessColor::*;
+    use data::PieceType::*;
+
+    match (color, piece_type) {
+        (White, Pawn) => 'P',
+        (White, King) => 'K',
+        (White, Queen) => 'Q',
+        (White, Bishop) => 'B',
+        (White, Rook) => 'R',
+        (White, Knight) => 'N',
+        (Black, Pawn) => 'p',
+        (Black, King) => 'k',
+        (Black, Queen) => 'q',
+        (Black, Bishop) => 'b',
+        (Black, Rook) => 'r',
+        (Black, Knight) => 'n',
+    }
+}
+
+pub fn piece_from_fen_char(ch: char) -> Option<(ChessColor, PieceType)> {
+    let color = if ch.is_uppercase() {
+        ChessColor::White
+    }
+    else {
+        ChessColor::Black
+    };
+
+    let piece_type = match ch.to_ascii_lowercase() {
+        'p' => PieceType::Pawn,
+        'n' => PieceType::Knight,
+        'b' => PieceType::Bishop,
+        'r' => PieceType::Rook,
+        'q' => PieceType::Queen,
+        'k' => PieceType::King,
+        _ => return None,
+    };
+
+    Some((color, piece_type))
+}
+
+
+const CASTLING_RIGHTS: [(ChessColor, i32, char); 4] = [
+    (ChessColor::White, 7, 'K'),
+    (ChessColor::White, 0, 'Q'),
+    (ChessColor::Black, 7, 'k'),
+    (ChessColor::Black, 0, 'q'),
+];
+
+pub fn back_rank(color: ChessColor) -> i32 {
+    match color {
+        ChessColor::White => 0,
+        ChessColor::Black => 7,
+    }
+}
+
+fn pawn_rank(color: ChessColor) -> i32 {
+    match color {
+        ChessColor::White => 1,
+        ChessColor::Black => 6,
+    }
+}
+
+fn unmoved_piece_at(
+    position: Vec2<i32>,
+    color: ChessColor,
+    piece_type: PieceType,
+    pieces: &[Piece],
+) -> bool {
+    match piece_at(position, pieces) {
+        Some(index) => {
+            let piece = &pieces[index];
+            piece.color == color && piece.piece_type == piece_type && !piece.moved
+        }
+        None => false,
+    }
+}
+
+pub fn castling_rights(pieces: &[Piece]) -> String {
+    let mut rights = String::with_capacity(4);
+
+    for &(color, rook_x, ch) in &CASTLING_RIGHTS {
+        let y = back_rank(color);
+        let king_ready =
+            unmoved_piece_at(vec2(4, y), color, PieceType::King, pieces);
+        let rook_ready =
+            unmoved_piece_at(vec2(rook_x, y), color, PieceType::Rook, pieces);
+
+        if king_ready && rook_ready {
+            rights.push(ch);
+        }
+    }
+
+    if rights.is_empty() {
+        rights.push('-');
+    }
+
+    rights
+}
+
+/// Returns the en passant target square only if the pawn that made the
+/// double push is still there to be captured.
+pub fn en_passant_target(
+    pieces: &[Piece],
+    whos_turn: ChessColor,
+    move_state: &MoveState,
+) -> Option<Vec2<i32>> {
+    let target = move_state.en_passant?;
+    let (x, y) = target.as_tuple();
+
+    let (pushed_color, pawn_y) = match y {
+        2 => (ChessColor::White, 3),
+        5 => (ChessColor::Black, 4),
+        _ => return None,
+    };
+
+    let pawn_present = pieces.iter().any(|piece| {
+        piece.position == vec2(x, pawn_y) && piece.color == pushed_color
+            && piece.piece_type == PieceType::Pawn
+    });
+
+    if pushed_color != whos_turn && pawn_present
+        && piece_at(target, pieces).is_none()
+    {
+        Some(target)
+    }
+    else {
+        None
+    }
+}
+
+
+pub fn generate_fen(
+    pieces: &[Piece],
+    whos_turn: ChessColor,
+    move_state: &MoveState,
+) -> String {
     use std::fmt::Write;
 
     let mut buffer = String::with_capacity(128);
@@ -25,30 +285,13 @@ pub fn generate_fen(pieces: &[Piece], whos_turn: ChessColor) -> String {
         for x in 0..8 {
             match piece_at(vec2(x, 7 - y).as_i32(), pieces) {
                 Some(index) => {
-                    use ChessColor::*;
-                    use PieceType::*;
-
                     if empty_stretch > 0 {
                         write!(buffer, "{}", empty_stretch).unwrap();
                         empty_stretch = 0;
                     }
 
                     let piece = &pieces[index];
-                    let ch = match (piece.color, piece.piece_type) {
-                        (White, Pawn) => "P",
-                        (White, King) => "K",
-                        (White, Queen) => "Q",
-                        (White, Bishop) => "B",
-                        (White, Rook) => "R",
-                        (White, Knight) => "N",
-                        (Black, Pawn) => "p",
-                        (Black, King) => "k",
-                        (Black, Queen) => "q",
-                        (Black, Bishop) => "b",
-                        (Black, Rook) => "r",
-                        (Black, Knight) => "n",
-                    };
-                    buffer.push_str(ch);
+                    buffer.push(fen_char(piece.color, piece.piece_type));
                 }
                 None => {
                     empty_stretch += 1;
@@ -71,72 +314,388 @@ pub fn generate_fen(pieces: &[Piece], whos_turn: ChessColor) -> String {
         ChessColor::Black => buffer.push_str(" b "),
     }
 
-    buffer.push_str("KQkq - 0 1");
+    buffer.push_str(&castling_rights(pieces));
+
+    match en_passant_target(pieces, whos_turn, move_state) {
+        Some(target) => write!(buffer, " {}", square_name(target)).unwrap(),
+        None => buffer.push_str(" -"),
+    }
+
+    write!(
+        buffer,
+        " {} {}",
+        move_state.halfmove_clock, move_state.fullmove_number
+    ).unwrap();
 
     buffer
 }
 
 
-pub fn decide_move(pieces: &[Piece], whos_turn: ChessColor) -> BitMove {
-    use pleco_engine::{engine::PlecoSearcher, time::uci_timer::PreLimits};
+pub fn parse_fen(fen: &str) -> Result<ParsedFen, String> {
+    let mut fields = fen.split_whitespace();
 
-    let fen = generate_fen(&pieces, whos_turn);
-    let board = Board::from_fen(&fen).unwrap();
+    let placement = fields.next().ok_or("FEN is empty")?;
+    let turn = fields.next().unwrap_or("w");
+    let castling = fields.next().unwrap_or("-");
+    let en_passant = fields.next().unwrap_or("-");
+    let halfmove_clock = fields
+        .next()
+        .unwrap_or("0")
+        .parse::<u32>()
+        .map_err(|_| "Invalid halfmove clock")?;
+    let fullmove_number = fields
+        .next()
+        .unwrap_or("1")
+        .parse::<u32>()
+        .map_err(|_| "Invalid fullmove number")?;
 
-    let mut limits = PreLimits::blank();
-    limits.depth = Some(3);
-    let mut searcher = PlecoSearcher::init(false);
+    let mut pieces = Vec::with_capacity(32);
 
-    searcher.search(&board, &limits);
+    let ranks = placement.split('/').collect::<Vec<_>>();
+    if ranks.len() != 8 {
+        return Err(format!("Expected 8 ranks, found {}", ranks.len()));
+    }
 
-    searcher.await_move()
-}
+    for (row, rank) in ranks.iter().enumerate() {
+        let y = 7 - row as i32;
+        let mut x = 0;
 
+        for ch in rank.chars() {
+            if let Some(skip) = ch.to_digit(10) {
+                x += skip as i32;
+                continue;
+            }
 
-pub fn piece_price(piece_type: PieceType) -> &'static PiecePrice {
-    const PAWN: PiecePrice = PiecePrice {
-        buy_price: 4,
-        discount_price: 3,
-        sell_price: 2,
-        unmoved_sell_price: 4,
-    };
-    const KNIGHT: PiecePrice = PiecePrice {
-        buy_price: 5,
-        discount_price: 3,
-        sell_price: 3,
-        unmoved_sell_price: 3,
+            let (color, piece_type) = piece_from_fen_char(ch)
+                .ok_or_else(|| format!("Invalid piece '{}'", ch))?;
+
+            let position = vec2(x, y);
+            if !::valid_square(position) {
+                return Err(format!("Rank {} is too long", y + 1));
+            }
+
+            let moved = match piece_type {
+                PieceType::Pawn => y != pawn_rank(color),
+                PieceType::King | PieceType::Rook => true,
+                _ => y != back_rank(color),
+            };
+
+            pieces.push(Piece {
+                position,
+                color,
+                piece_type,
+                moved,
+                animation: None,
+                delete_after_animation: false,
+            });
+            x += 1;
+        }
+
+        if x != 8 {
+            return Err(format!("Rank {} does not have 8 squares", y + 1));
+        }
+    }
+
+    if castling != "-" {
+        for ch in castling.chars() {
+            let &(color, rook_x, _) = CASTLING_RIGHTS
+                .iter()
+                .find(|&&(_, _, right)| right == ch)
+                .ok_or_else(|| format!("Invalid castling right '{}'", ch))?;
+
+            let y = back_rank(color);
+            for piece in &mut pieces {
+                let is_king = piece.piece_type == PieceType::King
+                    && piece.position == vec2(4, y);
+                let is_rook = piece.piece_type == PieceType::Rook
+                    && piece.position == vec2(rook_x, y);
+
+                if piece.color == color && (is_king || is_rook) {
+                    piece.moved = false;
+                }
+            }
+        }
+    }
+
+    let whos_turn = match turn {
+        "w" => ChessColor::White,
+        "b" => ChessColor::Black,
+        _ => return Err(format!("Invalid side to move '{}'", turn)),
     };
-    const ROOK: PiecePrice = PiecePrice {
-        buy_price: 6,
-        discount_price: 4,
-        sell_price: 3,
-        unmoved_sell_price: 3,
+
+    let en_passant = match en_passant {
+        "-" => None,
+        square => Some(
+            parse_square(square)
+                .ok_or_else(|| format!("Invalid en passant square '{}'", square))?,
+        ),
     };
-    const BISHOP: PiecePrice = PiecePrice {
-        buy_price: 7,
-        discount_price: 5,
-        sell_price: 4,
-        unmoved_sell_price: 4,
+
+    Ok(ParsedFen {
+        pieces,
+        whos_turn,
+        move_state: MoveState {
+            en_passant,
+            halfmove_clock,
+            fullmove_number,
+        },
+    })
+}
+
+
+pub const SEARCH_DEPTH: u16 = 3;
+
+pub fn decide_move(board: &Board) -> BitMove {
+    search_move(board, SEARCH_DEPTH, |_| true).unwrap()
+}
+
+/// Searches one depth at a time up to `max_depth`, asking `keep_going` before
+/// each one, and returns the best move from the deepest search that finished.
+pub fn search_move<F>(
+    board: &Board,
+    max_depth: u16,
+    mut keep_going: F,
+) -> Option<BitMove>
+where
+    F: FnMut(u16) -> bool,
+{
+    use pleco_engine::{engine::PlecoSearcher, time::uci_timer::PreLimits};
+
+    let mut searcher = PlecoSearcher::init(false);
+    let mut best_move = None;
+
+    for depth in 1..max_depth + 1 {
+        if !keep_going(depth) {
+            break;
+        }
+
+        let mut limits = PreLimits::blank();
+        limits.depth = Some(depth);
+        searcher.search(board, &limits);
+        best_move = Some(searcher.await_move());
+    }
+
+    best_move
+}
+
+
+/// Scores a position for the side to move, in centipawns, by searching
+/// `depth` plies ahead.
+pub fn evaluate(board: &Board, depth: u16) -> i32 {
+    use pleco::bots::alphabeta::alpha_beta_search;
+
+    const BOUND: i16 = 30_000;
+
+    let mut board = board.shallow_clone();
+    i32::from(alpha_beta_search(&mut board, -BOUND, BOUND, depth).score)
+}
+
+/// Whether the side to move in `fen` is in check.
+pub fn in_check(fen: &str) -> bool {
+    Board::from_fen(fen)
+        .map(|board| board.in_check())
+        .unwrap_or(false)
+}
+
+/// The square the moving piece lands on. Castles are shown as the king's
+/// destination, whichever square pleco uses to encode them.
+pub fn move_destination(mov: BitMove) -> Vec2<i32> {
+    let from = ::grid_from_u8(mov.get_src_u8());
+
+    if mov.is_castle() {
+        let x = if mov.is_king_castle() { 6 } else { 2 };
+        vec2(x, from.0[1])
+    }
+    else {
+        ::grid_from_u8(mov.get_dest_u8())
+    }
+}
+
+pub fn promotion_piece(mov: BitMove) -> Option<PieceType> {
+    use pleco::PieceType::*;
+
+    if !mov.is_promo() {
+        return None;
+    }
+
+    let piece = match mov.promo_piece() {
+        Q => PieceType::Queen,
+        R => PieceType::Rook,
+        B => PieceType::Bishop,
+        N => PieceType::Knight,
+        P => PieceType::Pawn,
+        _ => unreachable!("Invalid promotion was attempted."),
     };
-    const QUEEN: PiecePrice = PiecePrice {
-        buy_price: 9,
-        discount_price: 6,
-        sell_price: 5,
-        unmoved_sell_price: 5,
+
+    Some(piece)
+}
+
+pub fn find_move(
+    board: &Board,
+    from: Vec2<i32>,
+    to: Vec2<i32>,
+    promotion: Option<PieceType>,
+) -> Option<BitMove> {
+    let promotion = promotion.unwrap_or(PieceType::Queen);
+
+    board.generate_moves().iter().cloned().find(|&mov| {
+        let promotion_matches = match promotion_piece(mov) {
+            Some(piece) => piece == promotion,
+            None => true,
+        };
+
+        ::grid_from_u8(mov.get_src_u8()) == from && move_destination(mov) == to
+            && promotion_matches
+    })
+}
+
+/// Standard algebraic notation for a legal move, without any check or mate
+/// suffix. Must be called before the move is applied.
+pub fn move_san(board: &Board, pieces: &[Piece], mov: BitMove) -> String {
+    if mov.is_castle() {
+        let castle = if mov.is_king_castle() { "O-O" } else { "O-O-O" };
+        return castle.to_owned();
+    }
+
+    let from = ::grid_from_u8(mov.get_src_u8());
+    let to = move_destination(mov);
+    let piece_type_at = |position| {
+        piece_at(position, pieces).map(|index| pieces[index].piece_type)
     };
 
+    let piece_type = piece_type_at(from).unwrap_or(PieceType::Pawn);
+    let capture = mov.is_en_passant() || piece_at(to, pieces).is_some();
+    let from_name = square_name(from);
+
+    let mut san = String::with_capacity(8);
+
+    if piece_type == PieceType::Pawn {
+        if capture {
+            san.push_str(&from_name[0..1]);
+        }
+    }
+    else {
+        san.push(fen_char(ChessColor::White, piece_type));
+
+        let rivals = board
+            .generate_moves()
+            .iter()
+            .filter(|&&other| move_destination(other) == to)
+            .map(|other| ::grid_from_u8(other.get_src_u8()))
+            .filter(|&other_from| {
+                other_from != from && piece_type_at(other_from) == Some(piece_type)
+            })
+            .collect::<Vec<_>>();
+
+        if !rivals.is_empty() {
+            let shares_file = rivals.iter().any(|other| other.0[0] == from.0[0]);
+            let shares_rank = rivals.iter().any(|other| other.0[1] == from.0[1]);
+
+            if !shares_file {
+                san.push_str(&from_name[0..1]);
+            }
+            else if !shares_rank {
+                san.push_str(&from_name[1..2]);
+            }
+            else {
+                san.push_str(&from_name);
+            }
+        }
+    }
+
+    if capture {
+        san.push('x');
+    }
+
+    san.push_str(&square_name(to));
+
+    if let Some(promotion) = promotion_piece(mov) {
+        san.push('=');
+        san.push(fen_char(ChessColor::White, promotion));
+    }
+
+    san
+}
+
+pub fn move_action(mov: BitMove) -> Action {
+    Action::Move {
+        from: ::grid_from_u8(mov.get_src_u8()),
+        to: move_destination(mov),
+        promotion: promotion_piece(mov),
+    }
+}
+
+
+pub fn random_piece<R: Rng>(weights: &ShopWeights, rng: &mut R) -> PieceType {
+    use rand::distributions::{IndependentSample, Weighted, WeightedChoice};
+
+    let mut choices = [
+        Weighted {
+            weight: weights.pawn,
+            item: PieceType::Pawn,
+        },
+        Weighted {
+            weight: weights.knight,
+            item: PieceType::Knight,
+        },
+        Weighted {
+            weight: weights.rook,
+            item: PieceType::Rook,
+        },
+        Weighted {
+            weight: weights.bishop,
+            item: PieceType::Bishop,
+        },
+        Weighted {
+            weight: weights.queen,
+            item: PieceType::Queen,
+        },
+    ];
+    let wc = WeightedChoice::new(&mut choices);
+
+    wc.ind_sample(rng)
+}
+
+
+pub fn piece_price(
+    prices: &PiecePrices,
+    piece_type: PieceType,
+) -> &PiecePrice {
     match piece_type {
-        PieceType::Pawn => &PAWN,
-        PieceType::Knight => &KNIGHT,
-        PieceType::Rook => &ROOK,
-        PieceType::Bishop => &BISHOP,
-        PieceType::Queen => &QUEEN,
+        PieceType::Pawn => &prices.pawn,
+        PieceType::Knight => &prices.knight,
+        PieceType::Rook => &prices.rook,
+        PieceType::Bishop => &prices.bishop,
+        PieceType::Queen => &prices.queen,
         PieceType::King => unreachable!("Do not buy or sell kings!"),
     }
 }
 
-pub fn sell_price(piece_type: PieceType, moved: bool) -> u32 {
-    let price = piece_price(piece_type);
+pub const PURCHASABLE: [PieceType; 5] = [
+    PieceType::Pawn,
+    PieceType::Knight,
+    PieceType::Rook,
+    PieceType::Bishop,
+    PieceType::Queen,
+];
+
+pub fn cheapest_price(prices: &PiecePrices) -> u32 {
+    PURCHASABLE
+        .iter()
+        .map(|&piece_type| {
+            let price = piece_price(prices, piece_type);
+            price.buy_price.min(price.discount_price)
+        })
+        .min()
+        .unwrap()
+}
+
+pub fn sell_price(
+    prices: &PiecePrices,
+    piece_type: PieceType,
+    moved: bool,
+) -> u32 {
+    let price = piece_price(prices, piece_type);
     if moved {
         price.sell_price
     }
@@ -145,8 +704,8 @@ pub fn sell_price(piece_type: PieceType, moved: bool) -> u32 {
     }
 }
 
-pub fn buy_price(piece_for_sale: PieceForSale) -> u32 {
-    let price = piece_price(piece_for_sale.piece_type);
+pub fn buy_price(prices: &PiecePrices, piece_for_sale: PieceForSale) -> u32 {
+    let price = piece_price(prices, piece_for_sale.piece_type);
     if piece_for_sale.discounted {
         price.discount_price
     }
@@ -155,6 +714,39 @@ pub fn buy_price(piece_for_sale: PieceForSale) -> u32 {
     }
 }
 
+/// The square where buying a piece leaves the best position for its new owner,
+/// judged by searching `depth` plies ahead from each valid placement.
+pub fn best_purchase_placement(
+    pieces: &[Piece],
+    color: ChessColor,
+    move_state: &MoveState,
+    piece_type: PieceType,
+    depth: u16,
+) -> Option<Vec2<i32>> {
+    let mut placed = pieces.to_vec();
+
+    valid_purchase_placements(pieces, piece_type, color)
+        .into_iter()
+        .filter_map(|place| {
+            placed.push(Piece {
+                position: place,
+                color,
+                piece_type,
+                moved: false,
+                animation: None,
+                delete_after_animation: false,
+            });
+            let fen = generate_fen(&placed, color, move_state);
+            placed.pop();
+
+            Board::from_fen(&fen)
+                .ok()
+                .map(|board| (evaluate(&board, depth), place))
+        })
+        .max_by_key(|&(score, _)| score)
+        .map(|(_, place)| place)
+}
+
 pub fn valid_purchase_placements(
     pieces: &[Piece],
     piece_type: PieceType,
@@ -178,3 +770,194 @@ pub fn valid_purchase_placements(
 
     valid_purchase_placements
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use game::new_piece;
+
+    fn square(name: &str) -> Vec2<i32> {
+        parse_square(name).unwrap()
+    }
+
+    fn san_on(
+        board: &Board,
+        pieces: &[Piece],
+        from: &str,
+        to: &str,
+        promotion: Option<PieceType>,
+    ) -> String {
+        let mov = find_move(board, square(from), square(to), promotion).unwrap();
+        move_san(board, pieces, mov)
+    }
+
+    fn san(fen: &str, from: &str, to: &str) -> String {
+        let board = Board::from_fen(fen).unwrap();
+        san_on(&board, &parse_fen(fen).unwrap().pieces, from, to, None)
+    }
+
+    /// SAN for White's move with the board set up from `pieces` alone.
+    fn san_of(
+        pieces: &[Piece],
+        from: &str,
+        to: &str,
+        promotion: Option<PieceType>,
+    ) -> String {
+        let fen = generate_fen(pieces, ChessColor::White, &MoveState::default());
+        san_on(&Board::from_fen(&fen).unwrap(), pieces, from, to, promotion)
+    }
+
+    fn round_trip(fen: &str) -> String {
+        let parsed = parse_fen(fen).unwrap();
+        generate_fen(&parsed.pieces, parsed.whos_turn, &parsed.move_state)
+    }
+
+    #[test]
+    fn fen_survives_a_round_trip() {
+        let fens = [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2",
+            "r3k2r/8/8/8/8/8/8/R3K2R b Kq - 5 17",
+            "4k3/8/8/8/8/8/8/4K3 b - - 37 80",
+        ];
+
+        for fen in &fens {
+            assert_eq!(round_trip(fen), *fen);
+        }
+    }
+
+    #[test]
+    fn en_passant_target_needs_the_pushed_pawn() {
+        assert_eq!(
+            round_trip("4k3/8/8/8/8/8/8/4K3 w - e6 0 1"),
+            "4k3/8/8/8/8/8/8/4K3 w - - 0 1"
+        );
+        assert_eq!(
+            round_trip("4k3/8/8/3pP3/8/8/8/4K3 b - d6 0 1"),
+            "4k3/8/8/3pP3/8/8/8/4K3 b - - 0 1"
+        );
+    }
+
+    #[test]
+    fn moving_the_king_drops_both_rights() {
+        let mut parsed =
+            parse_fen("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1").unwrap();
+        let king = piece_at(vec2(4, 0), &parsed.pieces).unwrap();
+        parsed.pieces[king].moved = true;
+
+        assert_eq!(castling_rights(&parsed.pieces), "kq");
+    }
+
+    #[test]
+    fn bought_rooks_grant_castling_rights() {
+        let mut parsed = parse_fen("4k3/8/8/8/8/8/8/4K3 w K - 0 1").unwrap();
+        assert_eq!(castling_rights(&parsed.pieces), "-");
+
+        parsed
+            .pieces
+            .push(new_piece(vec2(7, 0), ChessColor::White, PieceType::Rook));
+        assert_eq!(castling_rights(&parsed.pieces), "K");
+
+        parsed
+            .pieces
+            .push(new_piece(vec2(0, 0), ChessColor::White, PieceType::Rook));
+        assert_eq!(castling_rights(&parsed.pieces), "KQ");
+    }
+
+    #[test]
+    fn malformed_fen_is_rejected() {
+        let fens = [
+            "",
+            "4k3/8/8/8/8/8/4K3 w - - 0 1",
+            "4k3/8/8/8/8/8/8/4K4 w - - 0 1",
+            "4k3/8/8/8/8/8/8/ppppppppp w - - 0 1",
+            "4k3/8/8/8/8/8/8/4X3 w - - 0 1",
+            "4k3/8/8/8/8/8/8/4K3 x - - 0 1",
+            "4k3/8/8/8/8/8/8/4K3 w Z - 0 1",
+            "4k3/8/8/8/8/8/8/4K3 w - z9 0 1",
+            "4k3/8/8/8/8/8/8/4K3 w - - x 1",
+            "4k3/8/8/8/8/8/8/4K3 w - - 0 -1",
+        ];
+
+        for fen in &fens {
+            assert!(parse_fen(fen).is_err(), "accepted {:?}", fen);
+        }
+    }
+
+    #[test]
+    fn san_for_pawn_moves_and_captures() {
+        assert_eq!(san("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", "e2", "e4"), "e4");
+        assert_eq!(san("4k3/8/8/3p4/4P3/8/8/4K3 w - - 0 1", "e4", "d5"), "exd5");
+        assert_eq!(
+            san("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5", "d6"),
+            "exd6"
+        );
+        assert_eq!(san("4k3/8/8/3n4/8/4N3/8/4K3 w - - 0 1", "e3", "d5"), "Nxd5");
+    }
+
+    #[test]
+    fn san_for_castling() {
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+
+        assert_eq!(san(fen, "e1", "g1"), "O-O");
+        assert_eq!(san(fen, "e1", "c1"), "O-O-O");
+    }
+
+    #[test]
+    fn san_for_promotions() {
+        let pieces = parse_fen("1r2k3/P7/8/8/8/8/8/4K3 w - - 0 1")
+            .unwrap()
+            .pieces;
+
+        assert_eq!(san_of(&pieces, "a7", "a8", None), "a8=Q");
+        assert_eq!(
+            san_of(&pieces, "a7", "a8", Some(PieceType::Knight)),
+            "a8=N"
+        );
+        assert_eq!(
+            san_of(&pieces, "a7", "b8", Some(PieceType::Rook)),
+            "axb8=R"
+        );
+    }
+
+    #[test]
+    fn find_move_tells_promotions_apart() {
+        let fen = "4k3/P7/8/8/8/8/8/4K3 w - - 0 1";
+        let board = Board::from_fen(fen).unwrap();
+        let (from, to) = (square("a7"), square("a8"));
+
+        let queen = find_move(&board, from, to, None).unwrap();
+        assert_eq!(promotion_piece(queen), Some(PieceType::Queen));
+
+        let bishop = find_move(&board, from, to, Some(PieceType::Bishop));
+        assert_eq!(promotion_piece(bishop.unwrap()), Some(PieceType::Bishop));
+
+        assert!(find_move(&board, from, square("b8"), None).is_none());
+    }
+
+    #[test]
+    fn san_disambiguates_by_file_then_rank() {
+        // Knights on b1 and f1 can both reach d2
+        assert_eq!(san("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1", "b1", "d2"), "Nbd2");
+        // Rooks on a1 and a5 can both reach a3
+        assert_eq!(san("4k3/8/8/R7/8/8/8/R3K3 w - - 0 1", "a1", "a3"), "R1a3");
+        // Queens on a1, c1 and a3 can all reach b2
+        assert_eq!(
+            san("4k3/8/8/8/8/Q7/8/Q1Q1K3 w - - 0 1", "a1", "b2"),
+            "Qa1b2"
+        );
+    }
+
+    #[test]
+    fn san_for_bought_pieces() {
+        let mut pieces = parse_fen("4k3/8/8/8/8/8/8/4K3 w K - 0 1")
+            .unwrap()
+            .pieces;
+        pieces.push(new_piece(square("b1"), ChessColor::White, PieceType::Knight));
+        pieces.push(new_piece(square("h1"), ChessColor::White, PieceType::Rook));
+
+        assert_eq!(san_of(&pieces, "b1", "c3", None), "Nc3");
+        assert_eq!(san_of(&pieces, "e1", "g1", None), "O-O");
+    }
+}
@@ -0,0 +1,189 @@
+use data::*;
+
+
+/// A chess clock for both sides, in seconds. Only the side to move's time
+/// runs down. `turn_time` is how long that side has been on its turn, and
+/// `action_time` how long since it last did anything.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Clock {
+    pub white: f32,
+    pub black: f32,
+    pub turn_time: f32,
+    pub action_time: f32,
+    pub control: TimeControl,
+}
+
+impl Clock {
+    pub fn new(control: TimeControl) -> Self {
+        Clock {
+            white: control.base,
+            black: control.base,
+            turn_time: 0.0,
+            action_time: 0.0,
+            control,
+        }
+    }
+
+    pub fn remaining(&self, color: ChessColor) -> f32 {
+        match color {
+            ChessColor::White => self.white,
+            ChessColor::Black => self.black,
+        }
+    }
+
+    fn remaining_mut(&mut self, color: ChessColor) -> &mut f32 {
+        match color {
+            ChessColor::White => &mut self.white,
+            ChessColor::Black => &mut self.black,
+        }
+    }
+
+    /// Runs `color`'s clock for `dt` seconds. True once it has run out.
+    pub fn tick(&mut self, color: ChessColor, dt: f32) -> bool {
+        self.turn_time += dt;
+        self.action_time += dt;
+
+        let remaining = self.remaining_mut(color);
+        *remaining = (*remaining - dt).max(0.0);
+        *remaining <= 0.0
+    }
+
+    /// Call when `color` sells or buys.
+    pub fn shop_action(&mut self, color: ChessColor) {
+        if !self.control.shop_actions_count {
+            let spent = self.action_time;
+            *self.remaining_mut(color) += spent;
+            self.turn_time -= spent;
+        }
+
+        self.action_time = 0.0;
+    }
+
+    /// Call once `color` has handed the turn over.
+    pub fn end_turn(&mut self, color: ChessColor) {
+        let bonus = match self.control.style {
+            ClockStyle::SuddenDeath => 0.0,
+            ClockStyle::Fischer => self.control.bonus,
+            ClockStyle::Bronstein => self.control.bonus.min(self.turn_time),
+        };
+
+        *self.remaining_mut(color) += bonus;
+        self.turn_time = 0.0;
+        self.action_time = 0.0;
+    }
+}
+
+
+/// Time as shown on the clock: minutes and seconds, with tenths of a second
+/// once it's getting short.
+pub fn format_time(seconds: f32) -> String {
+    let seconds = seconds.max(0.0);
+
+    if seconds < 10.0 {
+        format!("{:.1}", seconds)
+    }
+    else {
+        let whole = seconds.ceil() as u32;
+        format!("{}:{:02}", whole / 60, whole % 60)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(style: ClockStyle, base: f32, bonus: f32) -> Clock {
+        Clock::new(TimeControl {
+            name: "test".to_owned(),
+            style,
+            base,
+            bonus,
+            shop_actions_count: false,
+        })
+    }
+
+    #[test]
+    fn fischer_adds_the_whole_increment() {
+        let mut clock = clock(ClockStyle::Fischer, 60.0, 2.0);
+
+        assert!(!clock.tick(ChessColor::White, 5.0));
+        assert_eq!(clock.white, 55.0);
+        assert_eq!(clock.black, 60.0);
+
+        clock.end_turn(ChessColor::White);
+        assert_eq!(clock.white, 57.0);
+        assert_eq!(clock.turn_time, 0.0);
+    }
+
+    #[test]
+    fn bronstein_gives_back_no_more_than_was_used() {
+        let mut clock = clock(ClockStyle::Bronstein, 300.0, 5.0);
+
+        clock.tick(ChessColor::White, 3.0);
+        clock.end_turn(ChessColor::White);
+        assert_eq!(clock.white, 300.0);
+
+        clock.tick(ChessColor::Black, 8.0);
+        clock.end_turn(ChessColor::Black);
+        assert_eq!(clock.black, 297.0);
+    }
+
+    #[test]
+    fn sudden_death_adds_nothing() {
+        let mut clock = clock(ClockStyle::SuddenDeath, 900.0, 0.0);
+
+        clock.tick(ChessColor::White, 10.0);
+        clock.end_turn(ChessColor::White);
+        assert_eq!(clock.white, 890.0);
+    }
+
+    #[test]
+    fn shop_actions_are_refunded_unless_they_count() {
+        let mut clock = clock(ClockStyle::SuddenDeath, 60.0, 0.0);
+
+        clock.tick(ChessColor::White, 4.0);
+        clock.shop_action(ChessColor::White);
+        assert_eq!(clock.white, 60.0);
+        assert_eq!(clock.turn_time, 0.0);
+        assert_eq!(clock.action_time, 0.0);
+
+        clock.tick(ChessColor::White, 3.0);
+        assert_eq!(clock.white, 57.0);
+
+        clock.control.shop_actions_count = true;
+        clock.tick(ChessColor::White, 2.0);
+        clock.shop_action(ChessColor::White);
+        assert_eq!(clock.white, 55.0);
+        assert_eq!(clock.turn_time, 5.0);
+    }
+
+    #[test]
+    fn refunded_time_doesnt_earn_a_delay() {
+        let mut clock = clock(ClockStyle::Bronstein, 60.0, 5.0);
+
+        clock.tick(ChessColor::White, 4.0);
+        clock.shop_action(ChessColor::White);
+        clock.tick(ChessColor::White, 2.0);
+        clock.end_turn(ChessColor::White);
+
+        assert_eq!(clock.white, 60.0);
+    }
+
+    #[test]
+    fn running_out_flags_and_stops_at_zero() {
+        let mut clock = clock(ClockStyle::Fischer, 60.0, 1.0);
+
+        assert!(!clock.tick(ChessColor::Black, 59.5));
+        assert!(clock.tick(ChessColor::Black, 2.0));
+        assert_eq!(clock.black, 0.0);
+        assert_eq!(clock.white, 60.0);
+    }
+
+    #[test]
+    fn time_is_shown_in_tenths_once_short() {
+        assert_eq!(format_time(125.0), "2:05");
+        assert_eq!(format_time(9.3), "9.3");
+        assert_eq!(format_time(-1.0), "0.0");
+    }
+}
@@ -1,23 +1,178 @@
 pub use gen_config::{Config, CONFIG};
 use std::borrow::Cow;
 
+use game;
+use rng;
+
+/// The config to play with. A config that can't be read or doesn't pass
+/// `validate_config` is reported, and the one built into the game used
+/// instead.
 pub fn load_config() -> Cow<'static, Config> {
+    reload_config(Cow::Borrowed(&CONFIG))
+}
+
+/// Reads the config again, so edits show up without restarting in debug
+/// builds. Keeps `previous` if the edited config is broken.
+pub fn reload_config(previous: Cow<'static, Config>) -> Cow<'static, Config> {
+    match try_load_config() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid config: {}", err);
+            previous
+        }
+    }
+}
+
+pub fn try_load_config() -> Result<Cow<'static, Config>, String> {
+    let config = read_config()?;
+    validate_config(&config)?;
+    Ok(config)
+}
+
+fn read_config() -> Result<Cow<'static, Config>, String> {
     #[cfg(debug_assertions)]
     {
         use std::fs::File;
         use std::io::Read;
         use toml;
 
-        let mut file = File::open("assets/config.toml").unwrap();
+        let path = "assets/config.toml";
+        let mut file = File::open(path)
+            .map_err(|err| format!("Failed to open {}: {}", path, err))?;
 
         let mut buffer = String::new();
-        file.read_to_string(&mut buffer).unwrap();
+        file.read_to_string(&mut buffer)
+            .map_err(|err| format!("Failed to read {}: {}", path, err))?;
 
-        Cow::Owned(toml::from_str(&buffer).unwrap())
+        toml::from_str(&buffer)
+            .map(Cow::Owned)
+            .map_err(|err| err.to_string())
     }
 
     #[cfg(not(debug_assertions))]
     {
-        Cow::Borrowed(&CONFIG)
+        Ok(Cow::Borrowed(&CONFIG))
+    }
+}
+
+/// Checks the values that parse fine but would break the game.
+pub fn validate_config(config: &Config) -> Result<(), String> {
+    macro_rules! check_price {
+        ($piece:ident) => {{
+            let price = &config.prices.$piece;
+            let name = stringify!($piece);
+
+            if price.buy < 0
+                || price.discount < 0
+                || price.sell < 0
+                || price.unmoved_sell < 0
+            {
+                return Err(format!("prices.{} can't be negative", name));
+            }
+
+            if price.discount > price.buy {
+                return Err(format!(
+                    "prices.{} discount ({}) is more than its buy price ({})",
+                    name, price.discount, price.buy
+                ));
+            }
+        }};
+    }
+
+    check_price!(pawn);
+    check_price!(knight);
+    check_price!(rook);
+    check_price!(bishop);
+    check_price!(queen);
+
+    for name in &game::RULE_SETS {
+        game::rule_set(config, name)?;
+    }
+
+    for name in &game::TIME_CONTROLS {
+        game::time_control_preset(config, name)?;
+    }
+
+    if !game::RULE_SETS.iter().any(|&name| name == config.game.rules) {
+        return Err(format!(
+            "game.rules must be one of {}",
+            game::RULE_SETS.join(", ")
+        ));
+    }
+
+    let time_control = &config.game.time_control;
+    if !game::TIME_CONTROLS.iter().any(|&name| name == *time_control) {
+        return Err(format!(
+            "game.time_control must be one of {}",
+            game::TIME_CONTROLS.join(", ")
+        ));
+    }
+
+    let seed = config.game.seed.to_string();
+    if !seed.is_empty() && rng::parse_seed(&seed).is_none() {
+        return Err(format!(
+            "game.seed must be empty or a number from 0 to {}",
+            rng::MAX_SEED
+        ));
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_invalid(config: &Config, complaint: &str) {
+        match validate_config(config) {
+            Ok(()) => panic!("accepted a config without {}", complaint),
+            Err(err) => assert!(err.contains(complaint), "{}", err),
+        }
+    }
+
+    #[test]
+    fn built_in_config_is_valid() {
+        assert_eq!(validate_config(&CONFIG), Ok(()));
+    }
+
+    #[test]
+    fn discounts_cant_cost_more() {
+        let mut config = CONFIG.clone();
+        config.prices.knight.discount = config.prices.knight.buy + 1;
+
+        assert_invalid(&config, "prices.knight discount");
+    }
+
+    #[test]
+    fn rules_must_name_a_preset() {
+        let mut config = CONFIG.clone();
+        config.game.rules = Cow::Borrowed("chaos");
+        assert_invalid(&config, "game.rules");
+
+        let mut config = CONFIG.clone();
+        config.rules.drops.restock = Cow::Borrowed("sometimes");
+        assert_invalid(&config, "Unknown restock");
+    }
+
+    #[test]
+    fn clocks_need_time_on_them() {
+        let mut config = CONFIG.clone();
+        config.clocks.blitz.base = 0;
+        assert_invalid(&config, "clocks.blitz");
+
+        let mut config = CONFIG.clone();
+        config.game.time_control = Cow::Borrowed("glacial");
+        assert_invalid(&config, "game.time_control");
+    }
+
+    #[test]
+    fn seeds_must_fit_in_a_save() {
+        let mut config = CONFIG.clone();
+        config.game.seed = Cow::Owned(format!("{}0", rng::MAX_SEED));
+        assert_invalid(&config, "game.seed");
+
+        config.game.seed = Cow::Owned(rng::MAX_SEED.to_string());
+        assert_eq!(validate_config(&config), Ok(()));
     }
 }
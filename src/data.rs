@@ -1,25 +1,7 @@
 use okmath::*;
-use glium::texture::SrgbTexture2d;
-use graphics::Mesh;
 
 
-pub struct RenderCommand<'a> {
-    pub mesh: &'a Mesh,
-    pub color: Vec4<f32>,
-    pub mvp_matrix: Mat4<f32>,
-    pub colormap: &'a SrgbTexture2d,
-    pub texture_scale: Vec3<f32>,
-    pub texture_offset: Vec3<f32>,
-}
-
-pub struct UiRenderCommand<'a> {
-    pub colormap: &'a SrgbTexture2d,
-    pub pos: Vec3<f32>,
-    pub scale: f32,
-    pub angle: f32,
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Animation {
     pub from: Vec2<i32>,
     pub to: Vec2<i32>,
@@ -27,7 +9,7 @@ pub struct Animation {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Piece {
     pub position: Vec2<i32>,
     pub color: ChessColor,
@@ -37,19 +19,21 @@ pub struct Piece {
     pub delete_after_animation: bool,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PieceForSale {
     pub piece_type: PieceType,
     pub discounted: bool,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ChessColor {
     Black,
     White,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
 pub enum PieceType {
     Pawn,
     Knight,
@@ -59,14 +43,175 @@ pub enum PieceType {
     King,
 }
 
+/// The parts of a FEN record that can't be derived from the pieces alone.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MoveState {
+    pub en_passant: Option<Vec2<i32>>,
+    pub halfmove_clock: u32,
+    pub fullmove_number: u32,
+}
+
+impl Default for MoveState {
+    fn default() -> Self {
+        MoveState {
+            en_passant: None,
+            halfmove_clock: 0,
+            fullmove_number: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParsedFen {
+    pub pieces: Vec<Piece>,
+    pub whos_turn: ChessColor,
+    pub move_state: MoveState,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlayerKind {
+    Human,
+    Ai,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Players {
+    pub white: PlayerKind,
+    pub black: PlayerKind,
+}
+
+impl Players {
+    pub fn get(&self, color: ChessColor) -> PlayerKind {
+        match color {
+            ChessColor::White => self.white,
+            ChessColor::Black => self.black,
+        }
+    }
+
+    pub fn is_ai(&self, color: ChessColor) -> bool {
+        self.get(color) == PlayerKind::Ai
+    }
+}
+
+impl Default for Players {
+    fn default() -> Self {
+        Players {
+            white: PlayerKind::Human,
+            black: PlayerKind::Ai,
+        }
+    }
+}
+
+/// How well the AI plays. `think_time` is in seconds: the search stops going
+/// deeper once it has been used up, even short of `depth`. `buy_chance` is
+/// how likely the AI is to consider buying on a given turn, and `coin_value`
+/// is what it reckons a coin is worth in centipawns.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Difficulty {
+    pub name: String,
+    pub depth: u16,
+    pub think_time: f32,
+    pub blunder_chance: f32,
+    pub buy_chance: f32,
+    pub coin_value: u32,
+}
+
+/// The house rules a game is played under. `shop_size` is how many of the
+/// shop's slots are in use, and with `king_placement` each side may put its
+/// king anywhere along its back rank until it makes its first move.
+///
+/// With `kings_start_unmoved`, a king on its starting square counts as
+/// unmoved whatever the FEN's castling rights say, so it can castle with a
+/// rook bought later. A FEN can't grant that right with no rooks on the board.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuleSet {
+    pub name: String,
+    pub start_fen: String,
+    pub starting_coins: u32,
+    pub shop_size: usize,
+    pub restock: RestockPolicy,
+    pub capture_pays: CapturePays,
+    pub king_placement: bool,
+    pub kings_start_unmoved: bool,
+    pub purchases_end_turn: bool,
+}
+
+/// What happens to the shop at the end of each turn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestockPolicy {
+    /// Unsold pieces go on sale and empty slots are filled.
+    Discount,
+    /// Empty slots are filled and unsold pieces keep their price.
+    Refill,
+    /// Everything is cleared out for new stock.
+    Replace,
+}
+
+/// Who gets the coins for a captured piece.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CapturePays {
+    Victim,
+    Capturer,
+}
+
+/// How much time each side gets, in seconds. `bonus` is the increment for
+/// Fischer clocks and the delay for Bronstein ones. Unless
+/// `shop_actions_count` is set, time spent before selling or buying is handed
+/// back, so only the chess uses up the clock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimeControl {
+    pub name: String,
+    pub style: ClockStyle,
+    pub base: f32,
+    pub bonus: f32,
+    pub shop_actions_count: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClockStyle {
+    /// Each side's time has to last the whole game.
+    SuddenDeath,
+    /// The bonus is added after every turn.
+    Fischer,
+    /// Up to the bonus is given back after every turn, so a side can never
+    /// have more time than it started the turn with.
+    Bronstein,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum GameOutcome {
     Ongoing,
     Stalemate,
+    Draw(DrawReason),
     Victory(ChessColor),
+    /// The side whose clock ran out, losing the game.
+    OutOfTime(ChessColor),
+    /// The side that resigned.
+    Resigned(ChessColor),
+    AgreedDraw,
+}
+
+impl GameOutcome {
+    pub fn winner(&self) -> Option<ChessColor> {
+        match *self {
+            GameOutcome::Victory(color) => Some(color),
+            GameOutcome::OutOfTime(ChessColor::White) => Some(ChessColor::Black),
+            GameOutcome::OutOfTime(ChessColor::Black) => Some(ChessColor::White),
+            GameOutcome::Resigned(ChessColor::White) => Some(ChessColor::Black),
+            GameOutcome::Resigned(ChessColor::Black) => Some(ChessColor::White),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DrawReason {
+    Repetition,
+    FiftyMoves,
+    InsufficientMaterial,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Copy, Clone)]
 pub struct PiecePrice {
     pub buy_price: u32,
     pub discount_price: u32,
@@ -74,9 +219,69 @@ pub struct PiecePrice {
     pub unmoved_sell_price: u32,
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum ControlState {
-    Idle,
-    SelectedPieceIndex(usize),
-    SelectedPurchaseIndex(usize),
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PiecePrices {
+    pub pawn: PiecePrice,
+    pub knight: PiecePrice,
+    pub rook: PiecePrice,
+    pub bishop: PiecePrice,
+    pub queen: PiecePrice,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ShopWeights {
+    pub pawn: u32,
+    pub knight: u32,
+    pub rook: u32,
+    pub bishop: u32,
+    pub queen: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    Move {
+        from: Vec2<i32>,
+        to: Vec2<i32>,
+        promotion: Option<PieceType>,
+    },
+    Sell(Vec2<i32>),
+    Buy {
+        slot: usize,
+        place: Vec2<i32>,
+    },
+    /// Unlike the actions above, the ones below can be taken by either side
+    /// at any time, so they say who's taking them.
+    Resign(ChessColor),
+    Offer(ChessColor, Offer),
+    /// Accepts the other side's offer, or declines it if `false`.
+    Answer(ChessColor, bool),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Offer {
+    Draw,
+    /// Asks to take back the side's last action, and anything the other side
+    /// has done since.
+    TakeBack,
+}
+
+/// An offer waiting on an answer. It lapses once the side it was made to
+/// ends its turn without answering.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingOffer {
+    pub from: ChessColor,
+    pub offer: Offer,
+}
+
+/// A record of an action that was actually carried out, with enough detail to
+/// write it out without replaying the game. `shop` is what was for sale once
+/// the action (and any restock it caused) was done.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub action: Action,
+    pub color: ChessColor,
+    pub piece_type: PieceType,
+    pub coins: u32,
+    pub san: Option<String>,
+    pub shop: Vec<Option<PieceForSale>>,
 }
@@ -0,0 +1,289 @@
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use pleco::{BitMove, Board};
+
+use chess;
+use data::*;
+
+
+/// Something that can pick a chess move. The shop is none of its business:
+/// it only ever sees an ordinary chess position.
+pub trait Engine: Send {
+    /// Searches the position up to `depth` plies deep. `keep_going` is asked as
+    /// each depth starts, and returning false stops the search early with the
+    /// best move found so far, if there is one.
+    fn search(
+        &mut self,
+        board: &Board,
+        depth: u16,
+        keep_going: &mut FnMut(u16) -> bool,
+    ) -> Result<Option<BitMove>, String>;
+}
+
+
+/// The engine bundled with the game.
+#[derive(Debug, Default)]
+pub struct PlecoEngine;
+
+impl Engine for PlecoEngine {
+    fn search(
+        &mut self,
+        board: &Board,
+        depth: u16,
+        keep_going: &mut FnMut(u16) -> bool,
+    ) -> Result<Option<BitMove>, String> {
+        Ok(chess::search_move(board, depth, keep_going))
+    }
+}
+
+
+/// Any external engine speaking UCI, run as a child process.
+pub struct UciEngine {
+    name: String,
+    process: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+}
+
+impl UciEngine {
+    /// Starts the engine at `path` and waits for it to be ready.
+    pub fn new(path: &str) -> Result<Self, String> {
+        let mut process = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("Couldn't start {}: {}", path, err))?;
+
+        let input = process.stdin.take().unwrap();
+        let output = BufReader::new(process.stdout.take().unwrap());
+
+        let mut engine = UciEngine {
+            name: path.to_owned(),
+            process,
+            input,
+            output,
+        };
+
+        engine.send("uci")?;
+        loop {
+            let line = engine.receive()?;
+
+            if line.starts_with("id name ") {
+                engine.name = line["id name ".len()..].trim().to_owned();
+            }
+            else if line.trim() == "uciok" {
+                break;
+            }
+        }
+
+        engine.send("isready")?;
+        while engine.receive()?.trim() != "readyok" {}
+
+        Ok(engine)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&mut self, command: &str) -> Result<(), String> {
+        writeln!(self.input, "{}", command)
+            .and_then(|_| self.input.flush())
+            .map_err(|err| format!("{}: {}", self.name, err))
+    }
+
+    fn receive(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+
+        match self.output.read_line(&mut line) {
+            Ok(0) => Err(format!("{} exited", self.name)),
+            Ok(_) => Ok(line),
+            Err(err) => Err(format!("{}: {}", self.name, err)),
+        }
+    }
+}
+
+impl Engine for UciEngine {
+    fn search(
+        &mut self,
+        board: &Board,
+        depth: u16,
+        keep_going: &mut FnMut(u16) -> bool,
+    ) -> Result<Option<BitMove>, String> {
+        if !keep_going(1) {
+            return Ok(None);
+        }
+
+        self.send(&format!("position fen {}", board.fen()))?;
+        self.send(&format!("go depth {}", depth))?;
+
+        let mut stopped = false;
+        let mut reached = 1;
+
+        loop {
+            let line = self.receive()?;
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                Some("info") => {
+                    let searching = words
+                        .skip_while(|&word| word != "depth")
+                        .nth(1)
+                        .and_then(|word| word.parse::<u16>().ok());
+
+                    if let Some(searching) = searching {
+                        if searching > reached && !stopped {
+                            reached = searching;
+                            if !keep_going(searching) {
+                                self.send("stop")?;
+                                stopped = true;
+                            }
+                        }
+                    }
+                }
+                Some("bestmove") => {
+                    let text = words.next().unwrap_or("(none)");
+
+                    if text == "(none)" || text == "0000" {
+                        return Ok(None);
+                    }
+
+                    return match chess::parse_action(text) {
+                        Some(Action::Move {
+                            from,
+                            to,
+                            promotion,
+                        }) => {
+                            let mov = chess::find_move(board, from, to, promotion);
+                            let name = &self.name;
+                            mov.map(Some).ok_or_else(|| {
+                                format!("{} played illegal move {}", name, text)
+                            })
+                        }
+                        _ => Err(format!("{} sent bad move {}", self.name, text)),
+                    };
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Drop for UciEngine {
+    fn drop(&mut self) {
+        // Don't hang waiting for an engine that ignores `quit`
+        let _ = self.send("quit");
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    use super::*;
+
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+    /// Starts a shell script standing in for an engine. `on_go` is run for
+    /// each `go`, and anything it doesn't handle itself is ignored.
+    fn stub_engine(name: &str, on_go: &str) -> UciEngine {
+        let script = format!(
+            "#!/bin/sh\n\
+             while read command; do\n\
+             case \"$command\" in\n\
+             uci) echo 'id name Stub'; echo uciok ;;\n\
+             isready) echo readyok ;;\n\
+             go*) {} ;;\n\
+             quit) exit 0 ;;\n\
+             esac\n\
+             done\n",
+            on_go
+        );
+
+        let path = env::temp_dir()
+            .join(format!("purchess_stub_{}_{}", name, process::id()));
+        File::create(&path)
+            .and_then(|mut file| file.write_all(script.as_bytes()))
+            .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let engine = UciEngine::new(path.to_str().unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+        engine
+    }
+
+    fn search(engine: &mut UciEngine) -> Result<Option<BitMove>, String> {
+        let board = Board::from_fen(START).unwrap();
+        engine.search(&board, 3, &mut |_| true)
+    }
+
+    #[test]
+    fn reads_the_name_and_best_move() {
+        let mut engine = stub_engine(
+            "bestmove",
+            "echo 'info depth 1 score cp 10'; \
+             echo 'info depth 2 score cp 20'; \
+             echo 'info depth 3 score cp 15 pv e2e4'; \
+             echo 'bestmove e2e4 ponder e7e5'",
+        );
+
+        assert_eq!(engine.name(), "Stub");
+
+        let mov = search(&mut engine).unwrap().unwrap();
+        assert_eq!(
+            chess::move_action(mov),
+            chess::parse_action("e2e4").unwrap()
+        );
+    }
+
+    #[test]
+    fn illegal_moves_are_errors() {
+        let mut engine = stub_engine("illegal", "echo 'bestmove e2e5'");
+
+        let err = search(&mut engine).unwrap_err();
+        assert!(err.contains("illegal move e2e5"), "{}", err);
+    }
+
+    #[test]
+    fn no_move_is_none() {
+        let mut engine = stub_engine("none", "echo 'bestmove (none)'");
+
+        assert!(search(&mut engine).unwrap().is_none());
+    }
+
+    #[test]
+    fn stops_when_told_to() {
+        // Only answers once it's told to stop, and says whether it was
+        let mut engine = stub_engine(
+            "stop",
+            "echo 'info depth 1'; \
+             echo 'info depth 2'; \
+             read command; \
+             if [ \"$command\" = stop ]; then echo 'bestmove d2d4'; \
+             else echo 'bestmove (none)'; fi",
+        );
+
+        let board = Board::from_fen(START).unwrap();
+        let mut asked = Vec::new();
+        let mov = engine
+            .search(&board, 5, &mut |depth| {
+                asked.push(depth);
+                depth < 2
+            })
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(asked, vec![1, 2]);
+        assert_eq!(
+            chess::move_action(mov),
+            chess::parse_action("d2d4").unwrap()
+        );
+    }
+}
@@ -0,0 +1,1558 @@
+use okmath::*;
+use pleco::BitMove;
+
+use board::SyncedBoard;
+use chess;
+use clock::Clock;
+use config::Config;
+use data::*;
+use rng::{self, GameRng};
+
+
+pub const SHOP_SIZE: usize = 3;
+
+
+#[derive(Debug, Default)]
+pub struct ActionResult {
+    pub moved: Vec<(Vec2<i32>, Vec2<i32>)>,
+    pub removed: Vec<Piece>,
+    pub purchased: Option<(usize, Vec2<i32>)>,
+    /// Set when a resignation, offer or answer went through, since those
+    /// don't touch the board.
+    pub social: bool,
+}
+
+impl ActionResult {
+    /// True if the action was refused and nothing happened.
+    pub fn is_empty(&self) -> bool {
+        self.moved.is_empty()
+            && self.removed.is_empty()
+            && self.purchased.is_none()
+            && !self.social
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct GameState {
+    pub pieces: Vec<Piece>,
+    pub pieces_for_sale: [Option<PieceForSale>; SHOP_SIZE],
+    pub white_coins: u32,
+    pub black_coins: u32,
+    pub whos_turn: ChessColor,
+    pub move_state: MoveState,
+    pub board: SyncedBoard,
+    pub game_outcome: GameOutcome,
+    pub players: Players,
+    pub difficulty: Difficulty,
+    pub rules: RuleSet,
+    pub shop_weights: ShopWeights,
+    pub prices: PiecePrices,
+    pub start_fen: String,
+    pub start_shop: [Option<PieceForSale>; SHOP_SIZE],
+    pub history: Vec<LogEntry>,
+    pub rng: GameRng,
+    pub clock: Option<Clock>,
+    pub offer: Option<PendingOffer>,
+}
+
+impl GameState {
+    /// A new game, seeded from the config if it fixes a seed.
+    pub fn new(config: &Config) -> Self {
+        let seed = config_seed(config).unwrap_or_else(|| GameRng::random().seed());
+        GameState::new_seeded(config, seed)
+    }
+
+    pub fn new_seeded(config: &Config, seed: u64) -> Self {
+        GameState::with_rules(config, rules(config), seed)
+    }
+
+    /// A new game under `rules`, which must have come from `rule_set` so its
+    /// starting position is known to be good.
+    pub fn with_rules(config: &Config, rules: RuleSet, seed: u64) -> Self {
+        let fen = rules.start_fen.clone();
+        GameState::from_fen_with_rules(config, &fen, rules, seed).unwrap()
+    }
+
+    pub fn from_fen(config: &Config, fen: &str) -> Result<Self, String> {
+        GameState::from_fen_seeded(config, fen, GameRng::random().seed())
+    }
+
+    pub fn from_fen_seeded(
+        config: &Config,
+        fen: &str,
+        seed: u64,
+    ) -> Result<Self, String> {
+        GameState::from_fen_with_rules(config, fen, rules(config), seed)
+    }
+
+    pub fn from_fen_with_rules(
+        config: &Config,
+        fen: &str,
+        rules: RuleSet,
+        seed: u64,
+    ) -> Result<Self, String> {
+        let parsed = parse_start_fen(fen, &rules)?;
+        let mut state = GameState::with_pieces(
+            config,
+            parsed.pieces,
+            parsed.whos_turn,
+            parsed.move_state,
+            rules,
+            GameRng::new(seed),
+        )?;
+
+        state.game_outcome = state.detect_outcome();
+        Ok(state)
+    }
+
+    fn with_pieces(
+        config: &Config,
+        pieces: Vec<Piece>,
+        whos_turn: ChessColor,
+        move_state: MoveState,
+        rules: RuleSet,
+        mut rng: GameRng,
+    ) -> Result<Self, String> {
+        let shop_weights = shop_weights(config);
+        let start_fen = chess::generate_fen(&pieces, whos_turn, &move_state);
+        let board = SyncedBoard::new(&start_fen)?;
+
+        let mut pieces_for_sale = [None; SHOP_SIZE];
+        for slot in pieces_for_sale.iter_mut().take(rules.shop_size) {
+            *slot = Some(PieceForSale {
+                piece_type: chess::random_piece(&shop_weights, &mut rng),
+                discounted: false,
+            });
+        }
+
+        let difficulty = difficulty(config, &config.ai.difficulty.to_string())
+            .unwrap_or_else(|| difficulty(config, DIFFICULTIES[2]).unwrap());
+
+        Ok(GameState {
+            pieces,
+            pieces_for_sale,
+            white_coins: rules.starting_coins,
+            black_coins: rules.starting_coins,
+            whos_turn,
+            move_state,
+            board,
+            game_outcome: GameOutcome::Ongoing,
+            players: players(config),
+            difficulty,
+            rules,
+            shop_weights,
+            prices: piece_prices(config),
+            start_fen,
+            start_shop: pieces_for_sale,
+            history: Vec::new(),
+            rng,
+            clock: time_control(config).map(Clock::new),
+            offer: None,
+        })
+    }
+
+    pub fn coins(&self, color: ChessColor) -> u32 {
+        match color {
+            ChessColor::White => self.white_coins,
+            ChessColor::Black => self.black_coins,
+        }
+    }
+
+    pub fn coins_mut(&mut self, color: ChessColor) -> &mut u32 {
+        match color {
+            ChessColor::White => &mut self.white_coins,
+            ChessColor::Black => &mut self.black_coins,
+        }
+    }
+
+    pub fn fen(&self) -> String {
+        chess::generate_fen(&self.pieces, self.whos_turn, &self.move_state)
+    }
+
+    pub fn valid_destinations(&self, from: Vec2<i32>) -> Vec<Vec2<i32>> {
+        let (px, py) = from.as_tuple();
+        let piece_pos_u8 = (py * 8 + px) as u8;
+
+        let moves = self.board.pleco().generate_moves();
+
+        let mut destinations = Vec::new();
+        for &chessmove in moves.iter() {
+            let dest = chess::move_destination(chessmove);
+            let from_piece = chessmove.get_src_u8() == piece_pos_u8;
+
+            if from_piece && !destinations.contains(&dest) {
+                destinations.push(dest);
+            }
+        }
+
+        for x in 0..8 {
+            let dest = vec2(x, from.0[1]);
+
+            if self.is_king_placement(from, dest) && !destinations.contains(&dest) {
+                destinations.push(dest);
+            }
+        }
+
+        destinations
+    }
+
+    /// Whether moving from `from` to `to` is putting the king in place, as the
+    /// rules may allow until a side makes its first move. A king that can
+    /// castle to `to` does that instead.
+    pub fn is_king_placement(&self, from: Vec2<i32>, to: Vec2<i32>) -> bool {
+        let whos_turn = self.whos_turn;
+        let back_rank = chess::back_rank(whos_turn);
+
+        let own_king = match chess::piece_at(from, &self.pieces) {
+            Some(index) => {
+                let piece = &self.pieces[index];
+                piece.color == whos_turn && piece.piece_type == PieceType::King
+            }
+            None => false,
+        };
+        let has_moved = self
+            .history
+            .iter()
+            .any(|entry| entry.color == whos_turn && entry.san.is_some());
+
+        self.rules.king_placement
+            && own_king
+            && !has_moved
+            && from.0[1] == back_rank
+            && to.0[1] == back_rank
+            && chess::piece_at(to, &self.pieces).is_none()
+            && !chess::find_move(self.board.pleco(), from, to, None)
+                .map_or(false, |mov| mov.is_castle())
+    }
+
+    pub fn is_promotion(&self, from: Vec2<i32>, to: Vec2<i32>) -> bool {
+        let back_rank = to.0[1] == 0 || to.0[1] == 7;
+
+        match chess::piece_at(from, &self.pieces) {
+            Some(index) => {
+                self.pieces[index].piece_type == PieceType::Pawn && back_rank
+            }
+            None => false,
+        }
+    }
+
+    /// Once the game is over, only the social actions get a say - and those
+    /// check for themselves whether there's anything left to do.
+    pub fn apply(&mut self, action: Action) -> ActionResult {
+        let social = match action {
+            Action::Resign(_) | Action::Offer(..) | Action::Answer(..) => true,
+            _ => false,
+        };
+
+        if !social && self.game_outcome != GameOutcome::Ongoing {
+            return ActionResult::default();
+        }
+
+        match action {
+            Action::Move {
+                from,
+                to,
+                promotion,
+            } => self.move_piece(from, to, promotion),
+            Action::Sell(position) => self.sell_piece(position),
+            Action::Buy { slot, place } => self.buy_piece(slot, place),
+            Action::Resign(color) => self.resign(color),
+            Action::Offer(color, offer) => self.make_offer(color, offer),
+            Action::Answer(color, accept) => self.answer_offer(color, accept),
+        }
+    }
+
+    /// The side taking `action`. Moves, sales and purchases are always made
+    /// by the side to move.
+    pub fn actor(&self, action: Action) -> ChessColor {
+        match action {
+            Action::Resign(color)
+            | Action::Offer(color, _)
+            | Action::Answer(color, _) => color,
+            _ => self.whos_turn,
+        }
+    }
+
+    pub fn resign(&mut self, color: ChessColor) -> ActionResult {
+        let mut result = ActionResult::default();
+
+        if self.game_outcome == GameOutcome::Ongoing {
+            self.game_outcome = GameOutcome::Resigned(color);
+            self.offer = None;
+            result.social = true;
+        }
+
+        result
+    }
+
+    /// Puts an offer to the other side, if nothing is waiting on an answer
+    /// already. A take-back needs something to take back.
+    pub fn make_offer(&mut self, color: ChessColor, offer: Offer) -> ActionResult {
+        let mut result = ActionResult::default();
+
+        let has_acted = self.history.iter().any(|entry| entry.color == color);
+        let allowed = self.game_outcome == GameOutcome::Ongoing
+            && self.offer.is_none()
+            && (offer == Offer::Draw || has_acted);
+
+        if allowed {
+            self.offer = Some(PendingOffer { from: color, offer });
+            result.social = true;
+        }
+
+        result
+    }
+
+    pub fn answer_offer(
+        &mut self,
+        color: ChessColor,
+        accept: bool,
+    ) -> ActionResult {
+        let mut result = ActionResult::default();
+
+        let pending = match self.offer {
+            Some(pending) if pending.from == opponent(color) => pending,
+            _ => return result,
+        };
+
+        if self.game_outcome != GameOutcome::Ongoing {
+            return result;
+        }
+
+        if accept {
+            match pending.offer {
+                Offer::Draw => self.game_outcome = GameOutcome::AgreedDraw,
+                Offer::TakeBack => {
+                    if self.take_back(pending.from).is_err() {
+                        return result;
+                    }
+                }
+            }
+        }
+
+        self.offer = None;
+        result.social = true;
+        result
+    }
+
+    /// Rewinds the game to before `color`'s last action, by playing the
+    /// history up to there over again from the start. The shop is put back
+    /// as it was at each step, but the clocks and the generator carry on
+    /// from where they are, since time and luck can't be taken back.
+    fn take_back(&mut self, color: ChessColor) -> Result<(), String> {
+        let keep = self
+            .history
+            .iter()
+            .rposition(|entry| entry.color == color)
+            .ok_or_else(|| format!("{:?} has nothing to take back", color))?;
+
+        let parsed = parse_start_fen(&self.start_fen, &self.rules)?;
+        let mut state = self.clone();
+        state.pieces = parsed.pieces;
+        state.whos_turn = parsed.whos_turn;
+        state.move_state = parsed.move_state;
+        state.board = SyncedBoard::new(&self.start_fen)?;
+        state.pieces_for_sale = self.start_shop;
+        state.white_coins = self.rules.starting_coins;
+        state.black_coins = self.rules.starting_coins;
+        state.history.clear();
+        state.clock = None;
+        state.offer = None;
+        state.game_outcome = state.detect_outcome();
+
+        for entry in &self.history[..keep] {
+            if state.apply(entry.action).is_empty() {
+                return Err(format!("Couldn't replay {:?}", entry.action));
+            }
+            state.set_shop(&entry.shop);
+        }
+
+        state.rng = self.rng.clone();
+        state.clock = self.clock.take();
+        *self = state;
+        Ok(())
+    }
+
+    pub fn move_piece(
+        &mut self,
+        from: Vec2<i32>,
+        to: Vec2<i32>,
+        promotion: Option<PieceType>,
+    ) -> ActionResult {
+        if self.is_king_placement(from, to) {
+            return self.place_king(from, to);
+        }
+
+        let mov = chess::find_move(self.board.pleco(), from, to, promotion);
+
+        match mov {
+            Some(mov) => self.apply_move(mov),
+            None => ActionResult::default(),
+        }
+    }
+
+    /// Applies a legal move from pleco to the pieces, taking care of the
+    /// rook in a castle and the pawn taken en passant.
+    pub fn apply_move(&mut self, mov: BitMove) -> ActionResult {
+        let mut result = ActionResult::default();
+
+        let from = ::grid_from_u8(mov.get_src_u8());
+        let to = chess::move_destination(mov);
+
+        let moved_type = match chess::piece_at(from, &self.pieces) {
+            Some(index) => self.pieces[index].piece_type,
+            None => return result,
+        };
+        let mut san = chess::move_san(self.board.pleco(), &self.pieces, mov);
+        let mut refund = 0;
+
+        let captured_square = if mov.is_en_passant() {
+            Some(vec2(to.0[0], from.0[1]))
+        }
+        else if mov.is_castle() {
+            None
+        }
+        else {
+            Some(to)
+        };
+
+        let taken_index = captured_square
+            .and_then(|square| chess::piece_at(square, &self.pieces))
+            .and_then(|index| {
+                if self.pieces[index].color != self.whos_turn {
+                    Some(index)
+                }
+                else {
+                    None
+                }
+            });
+
+        if let Some(index) = taken_index {
+            let taken = self.pieces.swap_remove(index);
+            refund =
+                chess::sell_price(&self.prices, taken.piece_type, taken.moved);
+            let payee = match self.rules.capture_pays {
+                CapturePays::Victim => taken.color,
+                CapturePays::Capturer => self.whos_turn,
+            };
+            *self.coins_mut(payee) += refund;
+            result.removed.push(taken);
+        }
+
+        if mov.is_castle() {
+            let y = from.0[1];
+            let (rook_from, rook_to) = if mov.is_king_castle() {
+                (vec2(7, y), vec2(5, y))
+            }
+            else {
+                (vec2(0, y), vec2(3, y))
+            };
+
+            if let Some(index) = chess::piece_at(rook_from, &self.pieces) {
+                self.pieces[index].position = rook_to;
+                self.pieces[index].moved = true;
+                result.moved.push((rook_from, rook_to));
+            }
+        }
+
+        if let Some(index) = chess::piece_at(from, &self.pieces) {
+            let piece = &mut self.pieces[index];
+            piece.position = to;
+            piece.moved = true;
+
+            if let Some(promotion) = chess::promotion_piece(mov) {
+                piece.piece_type = promotion;
+            }
+        }
+        result.moved.insert(0, (from, to));
+
+        let pawn_moved = moved_type == PieceType::Pawn;
+        let double_push = pawn_moved && (to.0[1] - from.0[1]).abs() == 2;
+
+        self.move_state.en_passant = if double_push {
+            Some(vec2(from.0[0], (from.0[1] + to.0[1]) / 2))
+        }
+        else {
+            None
+        };
+
+        if pawn_moved || taken_index.is_some() {
+            self.move_state.halfmove_clock = 0;
+        }
+        else {
+            self.move_state.halfmove_clock += 1;
+        }
+
+        if self.whos_turn == ChessColor::Black {
+            self.move_state.fullmove_number += 1;
+        }
+
+        let color = self.whos_turn;
+        self.whos_turn = opponent(color);
+        self.board.apply_move(mov);
+        self.debug_assert_synced();
+
+        if self.board.pleco().checkmate() {
+            san.push('#');
+        }
+        else if self.board.pleco().in_check() {
+            san.push('+');
+        }
+
+        self.end_turn();
+
+        self.history.push(LogEntry {
+            action: chess::move_action(mov),
+            color,
+            piece_type: moved_type,
+            coins: refund,
+            san: Some(san),
+            shop: self.pieces_for_sale.to_vec(),
+        });
+
+        result
+    }
+
+    /// Moves the king along the back rank without it counting as a move.
+    fn place_king(&mut self, from: Vec2<i32>, to: Vec2<i32>) -> ActionResult {
+        let mut result = ActionResult::default();
+
+        let index = match chess::piece_at(from, &self.pieces) {
+            Some(index) => index,
+            None => return result,
+        };
+
+        self.pieces[index].position = to;
+        let fen = self.fen();
+
+        if self.board.edit(&fen).is_ok() {
+            result.moved.push((from, to));
+            self.history.push(LogEntry {
+                action: Action::Move {
+                    from,
+                    to,
+                    promotion: None,
+                },
+                color: self.whos_turn,
+                piece_type: PieceType::King,
+                coins: 0,
+                san: None,
+                shop: self.pieces_for_sale.to_vec(),
+            });
+        }
+        else {
+            self.pieces[index].position = from;
+        }
+
+        result
+    }
+
+    pub fn sell_piece(&mut self, position: Vec2<i32>) -> ActionResult {
+        let mut result = ActionResult::default();
+
+        if let Some(index) = chess::piece_at(position, &self.pieces) {
+            let sellable = {
+                let piece = &self.pieces[index];
+                piece.color == self.whos_turn && piece.piece_type != PieceType::King
+            };
+
+            if sellable {
+                let sold = self.pieces.swap_remove(index);
+                let fen = self.fen();
+
+                if self.board.edit(&fen).is_ok() {
+                    self.move_state.halfmove_clock = 0;
+                    let refund = chess::sell_price(
+                        &self.prices,
+                        sold.piece_type,
+                        sold.moved,
+                    );
+                    let whos_turn = self.whos_turn;
+                    *self.coins_mut(whos_turn) += refund;
+                    if let Some(ref mut clock) = self.clock {
+                        clock.shop_action(whos_turn);
+                    }
+                    self.history.push(LogEntry {
+                        action: Action::Sell(position),
+                        color: whos_turn,
+                        piece_type: sold.piece_type,
+                        coins: refund,
+                        san: None,
+                        shop: self.pieces_for_sale.to_vec(),
+                    });
+                    result.removed.push(sold);
+                    self.end_if_stuck();
+                }
+                else {
+                    self.pieces.push(sold);
+                }
+            }
+        }
+
+        result
+    }
+
+    pub fn buy_piece(&mut self, slot: usize, place: Vec2<i32>) -> ActionResult {
+        let mut result = ActionResult::default();
+
+        let piece_for_sale = match self.pieces_for_sale.get(slot) {
+            Some(&Some(piece_for_sale)) => piece_for_sale,
+            _ => return result,
+        };
+
+        let whos_turn = self.whos_turn;
+        let placements = chess::valid_purchase_placements(
+            &self.pieces,
+            piece_for_sale.piece_type,
+            whos_turn,
+        );
+        let price = chess::buy_price(&self.prices, piece_for_sale);
+
+        if placements.contains(&place) && price <= self.coins(whos_turn) {
+            self.pieces
+                .push(new_piece(place, whos_turn, piece_for_sale.piece_type));
+            let fen = self.fen();
+
+            // The turn can't be handed over with the buyer still in check
+            let ends_turn = self.rules.purchases_end_turn;
+            let allowed = !(ends_turn && chess::in_check(&fen));
+
+            if allowed && self.board.edit(&fen).is_ok() {
+                self.move_state.halfmove_clock = 0;
+                *self.coins_mut(whos_turn) -= price;
+                self.pieces_for_sale[slot] = None;
+                result.purchased = Some((slot, place));
+                if let Some(ref mut clock) = self.clock {
+                    clock.shop_action(whos_turn);
+                }
+
+                if ends_turn {
+                    self.pass_turn();
+                }
+
+                self.history.push(LogEntry {
+                    action: Action::Buy { slot, place },
+                    color: whos_turn,
+                    piece_type: piece_for_sale.piece_type,
+                    coins: price,
+                    san: None,
+                    shop: self.pieces_for_sale.to_vec(),
+                });
+
+                if !ends_turn {
+                    self.end_if_stuck();
+                }
+            }
+            else {
+                self.pieces.pop();
+            }
+        }
+
+        result
+    }
+
+    /// Where the side to move would do best to put the piece in shop `slot`.
+    pub fn suggested_placement(
+        &self,
+        slot: usize,
+        depth: u16,
+    ) -> Option<Vec2<i32>> {
+        let piece_for_sale = match self.pieces_for_sale.get(slot) {
+            Some(&Some(piece_for_sale)) => piece_for_sale,
+            _ => return None,
+        };
+
+        chess::best_purchase_placement(
+            &self.pieces,
+            self.whos_turn,
+            &self.move_state,
+            piece_for_sale.piece_type,
+            depth,
+        )
+    }
+
+    pub fn restock(&mut self) {
+        let restock = self.rules.restock;
+        let shop_size = self.rules.shop_size;
+
+        for (slot, piece) in self.pieces_for_sale.iter_mut().enumerate() {
+            if slot >= shop_size {
+                *piece = None;
+                continue;
+            }
+
+            let new_stock = match *piece {
+                Some(_) if restock == RestockPolicy::Replace => true,
+                Some(ref mut for_sale) => {
+                    if restock == RestockPolicy::Discount {
+                        for_sale.discounted = true;
+                    }
+                    false
+                }
+                None => true,
+            };
+
+            if new_stock {
+                *piece = Some(PieceForSale {
+                    piece_type: chess::random_piece(
+                        &self.shop_weights,
+                        &mut self.rng,
+                    ),
+                    discounted: false,
+                });
+            }
+        }
+    }
+
+    /// Puts a recorded shop in place of whatever the last restock came up
+    /// with, so a game can be followed exactly.
+    pub fn set_shop(&mut self, shop: &[Option<PieceForSale>]) {
+        for (slot, &piece) in self.pieces_for_sale.iter_mut().zip(shop) {
+            *slot = piece;
+        }
+
+        if let Some(entry) = self.history.last_mut() {
+            entry.shop = self.pieces_for_sale.to_vec();
+        }
+    }
+
+    /// Shop actions count as irreversible, like captures and pawn moves: they
+    /// reset the fifty-move count and start repetition counting afresh. For
+    /// insufficient material, a side can still mate if it could afford a new
+    /// piece by spending its coins and selling what it has.
+    pub fn detect_outcome(&self) -> GameOutcome {
+        let board = self.board.pleco();
+
+        if board.checkmate() {
+            GameOutcome::Victory(opponent(self.whos_turn))
+        }
+        else if board.stalemate() {
+            GameOutcome::Stalemate
+        }
+        else if self.board.repetitions() >= 3 {
+            GameOutcome::Draw(DrawReason::Repetition)
+        }
+        else if self.move_state.halfmove_clock >= 100 {
+            GameOutcome::Draw(DrawReason::FiftyMoves)
+        }
+        else if !self.can_still_mate(ChessColor::White)
+            && !self.can_still_mate(ChessColor::Black)
+        {
+            GameOutcome::Draw(DrawReason::InsufficientMaterial)
+        }
+        else {
+            GameOutcome::Ongoing
+        }
+    }
+
+    pub fn can_still_mate(&self, color: ChessColor) -> bool {
+        let mut minor_pieces = 0;
+        let mut purchasing_power = self.coins(color);
+
+        for piece in self.pieces.iter().filter(|piece| piece.color == color) {
+            match piece.piece_type {
+                PieceType::King => continue,
+                PieceType::Knight | PieceType::Bishop => minor_pieces += 1,
+                _ => return true,
+            }
+
+            purchasing_power +=
+                chess::sell_price(&self.prices, piece.piece_type, piece.moved);
+        }
+
+        minor_pieces >= 2
+            || purchasing_power >= chess::cheapest_price(&self.prices)
+    }
+
+    /// A sale or purchase can leave the side to move with no legal move. The
+    /// game only ends there if it couldn't buy its way out either, counting
+    /// what it could raise by selling up.
+    fn end_if_stuck(&mut self) {
+        if !self.board.pleco().generate_moves().is_empty() {
+            return;
+        }
+
+        let whos_turn = self.whos_turn;
+        let purchasing_power = self.coins(whos_turn)
+            + self
+                .pieces
+                .iter()
+                .filter(|piece| {
+                    piece.color == whos_turn && piece.piece_type != PieceType::King
+                })
+                .map(|piece| {
+                    chess::sell_price(&self.prices, piece.piece_type, piece.moved)
+                })
+                .sum::<u32>();
+
+        let can_buy = self.pieces_for_sale.iter().any(|for_sale| match *for_sale {
+            Some(for_sale) => {
+                chess::buy_price(&self.prices, for_sale) <= purchasing_power
+                    && !chess::valid_purchase_placements(
+                        &self.pieces,
+                        for_sale.piece_type,
+                        whos_turn,
+                    ).is_empty()
+            }
+            None => false,
+        });
+
+        if !can_buy {
+            self.game_outcome = self.detect_outcome();
+        }
+    }
+
+    /// Hands the move to the other side without a chess move being made.
+    fn pass_turn(&mut self) {
+        if self.whos_turn == ChessColor::Black {
+            self.move_state.fullmove_number += 1;
+        }
+
+        self.move_state.en_passant = None;
+        self.whos_turn = opponent(self.whos_turn);
+
+        let fen = self.fen();
+        self.board
+            .edit(&fen)
+            .expect("Passing the turn made an invalid position");
+        self.end_turn();
+    }
+
+    /// Runs the side to move's clock, if the game has one. Returns that side
+    /// if its time has just run out, but leaves it to the caller to `flag` it,
+    /// since only the host gets to decide that in a network game.
+    pub fn tick_clock(&mut self, dt: f32) -> Option<ChessColor> {
+        if self.game_outcome != GameOutcome::Ongoing {
+            return None;
+        }
+
+        let whos_turn = self.whos_turn;
+        let out_of_time = match self.clock {
+            Some(ref mut clock) => clock.tick(whos_turn, dt),
+            None => false,
+        };
+
+        if out_of_time {
+            Some(whos_turn)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Ends the game with `color` having lost on time.
+    pub fn flag(&mut self, color: ChessColor) {
+        if self.game_outcome == GameOutcome::Ongoing {
+            self.game_outcome = GameOutcome::OutOfTime(color);
+        }
+    }
+
+    /// Called with the turn already handed over.
+    fn end_turn(&mut self) {
+        let mover = opponent(self.whos_turn);
+        if let Some(ref mut clock) = self.clock {
+            clock.end_turn(mover);
+        }
+
+        // Playing on instead of answering turns an offer down
+        if self.offer.map_or(false, |pending| pending.from != mover) {
+            self.offer = None;
+        }
+
+        // A game already lost on time or given up stays that way
+        if self.game_outcome == GameOutcome::Ongoing {
+            self.game_outcome = self.detect_outcome();
+        }
+        self.restock();
+    }
+
+    fn debug_assert_synced(&self) {
+        if cfg!(debug_assertions) {
+            let ours = self.fen();
+            let theirs = self.board.pleco().fen();
+            let ours = ours.split_whitespace().take(2).collect::<Vec<_>>();
+            let theirs = theirs.split_whitespace().take(2).collect::<Vec<_>>();
+
+            assert_eq!(ours, theirs, "Board is out of sync");
+        }
+    }
+}
+
+
+/// Reads a FEN that a game under `rules` starts from.
+fn parse_start_fen(fen: &str, rules: &RuleSet) -> Result<ParsedFen, String> {
+    let mut parsed = chess::parse_fen(fen)?;
+
+    if rules.kings_start_unmoved {
+        for piece in &mut parsed.pieces {
+            let start = vec2(4, chess::back_rank(piece.color));
+            if piece.piece_type == PieceType::King && piece.position == start {
+                piece.moved = false;
+            }
+        }
+    }
+
+    Ok(parsed)
+}
+
+pub fn opponent(color: ChessColor) -> ChessColor {
+    match color {
+        ChessColor::White => ChessColor::Black,
+        ChessColor::Black => ChessColor::White,
+    }
+}
+
+pub const DIFFICULTIES: [&str; 4] =
+    ["beginner", "casual", "intermediate", "expert"];
+
+/// Looks up one of the `DIFFICULTIES` presets in the `[ai]` section of the
+/// config.
+pub fn difficulty(config: &Config, name: &str) -> Option<Difficulty> {
+    macro_rules! preset {
+        ($preset:expr) => {
+            Difficulty {
+                name: name.to_owned(),
+                depth: $preset.depth.max(1) as u16,
+                think_time: $preset.think_time,
+                blunder_chance: $preset.blunder_chance,
+                buy_chance: $preset.buy_chance,
+                coin_value: $preset.coin_value.max(0) as u32,
+            }
+        };
+    }
+
+    match name {
+        "beginner" => Some(preset!(config.ai.beginner)),
+        "casual" => Some(preset!(config.ai.casual)),
+        "intermediate" => Some(preset!(config.ai.intermediate)),
+        "expert" => Some(preset!(config.ai.expert)),
+        _ => None,
+    }
+}
+
+pub const RULE_SETS: [&str; 5] =
+    ["standard", "classic", "bounty", "drops", "kings"];
+
+/// The rules new games are played under, falling back to the standard ones if
+/// the config names rules that don't exist.
+pub fn rules(config: &Config) -> RuleSet {
+    rule_set(config, &config.game.rules.to_string())
+        .or_else(|_| rule_set(config, RULE_SETS[0]))
+        .unwrap()
+}
+
+/// One of the rule presets from the config, checked over.
+pub fn rule_set(config: &Config, name: &str) -> Result<RuleSet, String> {
+    macro_rules! preset {
+        ($preset:expr) => {
+            RuleSet {
+                name: name.to_owned(),
+                start_fen: $preset.start_fen.to_string(),
+                starting_coins: $preset.starting_coins.max(0) as u32,
+                shop_size: $preset.shop_size.max(0) as usize,
+                restock: match &*$preset.restock {
+                    "discount" => RestockPolicy::Discount,
+                    "refill" => RestockPolicy::Refill,
+                    "replace" => RestockPolicy::Replace,
+                    other => return Err(format!("Unknown restock '{}'", other)),
+                },
+                capture_pays: match &*$preset.capture_pays {
+                    "victim" => CapturePays::Victim,
+                    "capturer" => CapturePays::Capturer,
+                    other => {
+                        return Err(format!("Unknown capture_pays '{}'", other))
+                    }
+                },
+                king_placement: $preset.king_placement,
+                kings_start_unmoved: $preset.kings_start_unmoved,
+                purchases_end_turn: $preset.purchases_end_turn,
+            }
+        };
+    }
+
+    let rules = match name {
+        "standard" => preset!(config.rules.standard),
+        "classic" => preset!(config.rules.classic),
+        "bounty" => preset!(config.rules.bounty),
+        "drops" => preset!(config.rules.drops),
+        "kings" => preset!(config.rules.kings),
+        _ => return Err(format!("Unknown rules '{}'", name)),
+    };
+
+    if rules.shop_size == 0 || rules.shop_size > SHOP_SIZE {
+        return Err(format!(
+            "rules.{} shop_size must be from 1 to {}",
+            name, SHOP_SIZE
+        ));
+    }
+
+    SyncedBoard::new(&rules.start_fen)
+        .map_err(|err| format!("rules.{} start_fen: {}", name, err))?;
+
+    Ok(rules)
+}
+
+pub const TIME_CONTROLS: [&str; 5] = ["none", "bullet", "blitz", "rapid", "delay"];
+
+/// The clock new games are played with, if any. Unknown names in the config
+/// mean no clock.
+pub fn time_control(config: &Config) -> Option<TimeControl> {
+    time_control_preset(config, &config.game.time_control.to_string())
+        .unwrap_or(None)
+}
+
+/// One of the `TIME_CONTROLS` presets from the config, or `None` for
+/// untimed games.
+pub fn time_control_preset(
+    config: &Config,
+    name: &str,
+) -> Result<Option<TimeControl>, String> {
+    macro_rules! preset {
+        ($preset:expr) => {
+            TimeControl {
+                name: name.to_owned(),
+                style: match &*$preset.style {
+                    "sudden_death" => ClockStyle::SuddenDeath,
+                    "fischer" => ClockStyle::Fischer,
+                    "bronstein" => ClockStyle::Bronstein,
+                    other => return Err(format!("Unknown clock style '{}'", other)),
+                },
+                base: $preset.base as f32,
+                bonus: $preset.bonus as f32,
+                shop_actions_count: config.game.clock_shop_actions,
+            }
+        };
+    }
+
+    let control = match name {
+        "none" => return Ok(None),
+        "bullet" => preset!(config.clocks.bullet),
+        "blitz" => preset!(config.clocks.blitz),
+        "rapid" => preset!(config.clocks.rapid),
+        "delay" => preset!(config.clocks.delay),
+        _ => return Err(format!("Unknown time control '{}'", name)),
+    };
+
+    if control.base <= 0.0 || control.bonus < 0.0 {
+        return Err(format!(
+            "clocks.{} needs a positive base and a bonus of at least 0",
+            name
+        ));
+    }
+
+    Ok(Some(control))
+}
+
+/// Who plays each side in a new game, falling back to the default for names
+/// that aren't recognised.
+pub fn players(config: &Config) -> Players {
+    let defaults = Players::default();
+
+    Players {
+        white: parse_player_kind(&config.game.white_player.to_string())
+            .unwrap_or(defaults.white),
+        black: parse_player_kind(&config.game.black_player.to_string())
+            .unwrap_or(defaults.black),
+    }
+}
+
+/// The seed every new game starts from, if the config fixes one. Left empty,
+/// each game gets its own.
+pub fn config_seed(config: &Config) -> Option<u64> {
+    rng::parse_seed(&config.game.seed.to_string())
+}
+
+pub fn parse_player_kind(name: &str) -> Option<PlayerKind> {
+    match name {
+        "human" => Some(PlayerKind::Human),
+        "ai" => Some(PlayerKind::Ai),
+        _ => None,
+    }
+}
+
+pub fn shop_weights(config: &Config) -> ShopWeights {
+    ShopWeights {
+        pawn: config.weights.pawn as u32,
+        knight: config.weights.knight as u32,
+        rook: config.weights.rook as u32,
+        bishop: config.weights.bishop as u32,
+        queen: config.weights.queen as u32,
+    }
+}
+
+/// The prices from the config, which `config::load_config` has already
+/// checked make sense.
+pub fn piece_prices(config: &Config) -> PiecePrices {
+    macro_rules! price {
+        ($price:expr) => {
+            PiecePrice {
+                buy_price: $price.buy as u32,
+                discount_price: $price.discount as u32,
+                sell_price: $price.sell as u32,
+                unmoved_sell_price: $price.unmoved_sell as u32,
+            }
+        };
+    }
+
+    PiecePrices {
+        pawn: price!(config.prices.pawn),
+        knight: price!(config.prices.knight),
+        rook: price!(config.prices.rook),
+        bishop: price!(config.prices.bishop),
+        queen: price!(config.prices.queen),
+    }
+}
+
+pub fn new_piece(
+    position: Vec2<i32>,
+    color: ChessColor,
+    piece_type: PieceType,
+) -> Piece {
+    Piece {
+        position,
+        color,
+        piece_type,
+        moved: false,
+        animation: None,
+        delete_after_animation: false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::CONFIG;
+
+    const START: &str = "4k3/pppppppp/8/8/8/8/PPPPPPPP/4K3 w - - 0 1";
+
+    fn game(fen: &str) -> GameState {
+        GameState::from_fen_seeded(&CONFIG, fen, 1).unwrap()
+    }
+
+    fn square(name: &str) -> Vec2<i32> {
+        chess::parse_square(name).unwrap()
+    }
+
+    fn piece_on<'a>(state: &'a GameState, name: &str) -> Option<&'a Piece> {
+        chess::piece_at(square(name), &state.pieces)
+            .map(|index| &state.pieces[index])
+    }
+
+    fn move_action(from: &str, to: &str) -> Action {
+        Action::Move {
+            from: square(from),
+            to: square(to),
+            promotion: None,
+        }
+    }
+
+    fn for_sale(piece_type: PieceType) -> Option<PieceForSale> {
+        Some(PieceForSale {
+            piece_type,
+            discounted: false,
+        })
+    }
+
+    #[test]
+    fn move_hands_over_the_turn() {
+        let mut state = game(START);
+        let result = state.apply(move_action("e2", "e4"));
+
+        assert_eq!(result.moved, vec![(square("e2"), square("e4"))]);
+        assert_eq!(state.whos_turn, ChessColor::Black);
+        assert_eq!(state.move_state.en_passant, Some(square("e3")));
+        assert!(piece_on(&state, "e2").is_none());
+        assert_eq!(piece_on(&state, "e4").unwrap().piece_type, PieceType::Pawn);
+        assert_eq!(state.history.len(), 1);
+    }
+
+    #[test]
+    fn illegal_move_is_refused() {
+        let mut state = game(START);
+
+        assert!(state.apply(move_action("e2", "e5")).is_empty());
+        assert!(state.apply(move_action("e7", "e5")).is_empty());
+        assert_eq!(state.whos_turn, ChessColor::White);
+        assert!(state.history.is_empty());
+    }
+
+    #[test]
+    fn sell_refunds_and_keeps_the_turn() {
+        let mut state = game(START);
+        let refund = chess::sell_price(&state.prices, PieceType::Pawn, false);
+        let result = state.apply(Action::Sell(square("a2")));
+
+        assert_eq!(result.removed.len(), 1);
+        assert!(piece_on(&state, "a2").is_none());
+        assert_eq!(state.white_coins, refund);
+        assert_eq!(state.whos_turn, ChessColor::White);
+    }
+
+    #[test]
+    fn kings_and_enemy_pieces_cannot_be_sold() {
+        let mut state = game(START);
+
+        assert!(state.apply(Action::Sell(square("e1"))).is_empty());
+        assert!(state.apply(Action::Sell(square("a7"))).is_empty());
+        assert!(state.apply(Action::Sell(square("a4"))).is_empty());
+        assert_eq!(state.pieces.len(), 18);
+        assert_eq!(state.white_coins, 0);
+    }
+
+    #[test]
+    fn buy_places_the_piece_and_charges() {
+        let mut state = game(START);
+        state.white_coins = 20;
+        state.pieces_for_sale[0] = for_sale(PieceType::Knight);
+
+        let price =
+            chess::buy_price(&state.prices, state.pieces_for_sale[0].unwrap());
+        let result = state.apply(Action::Buy {
+            slot: 0,
+            place: square("b1"),
+        });
+
+        assert_eq!(result.purchased, Some((0, square("b1"))));
+        assert_eq!(piece_on(&state, "b1").unwrap().piece_type, PieceType::Knight);
+        assert_eq!(state.white_coins, 20 - price);
+        assert_eq!(state.pieces_for_sale[0], None);
+        assert_eq!(state.whos_turn, ChessColor::White);
+    }
+
+    #[test]
+    fn buy_is_refused_when_broke() {
+        let mut state = game(START);
+        state.pieces_for_sale[0] = for_sale(PieceType::Knight);
+
+        let result = state.apply(Action::Buy {
+            slot: 0,
+            place: square("b1"),
+        });
+
+        assert!(result.is_empty());
+        assert!(piece_on(&state, "b1").is_none());
+        assert!(state.pieces_for_sale[0].is_some());
+    }
+
+    #[test]
+    fn buy_is_refused_on_an_invalid_square() {
+        let mut state = game(START);
+        state.white_coins = 20;
+        state.pieces_for_sale[0] = for_sale(PieceType::Knight);
+        state.pieces_for_sale[1] = for_sale(PieceType::Pawn);
+
+        for &(slot, place) in &[(0, "d4"), (0, "e1"), (0, "b8"), (1, "b1")] {
+            let result = state.apply(Action::Buy {
+                slot,
+                place: square(place),
+            });
+            assert!(result.is_empty(), "Bought from slot {} on {}", slot, place);
+        }
+
+        assert_eq!(state.white_coins, 20);
+        assert_eq!(state.pieces.len(), 18);
+    }
+
+    #[test]
+    fn restock_discounts_unsold_pieces_and_fills_empty_slots() {
+        let mut state = game(START);
+        state.rules.restock = RestockPolicy::Discount;
+        state.pieces_for_sale = [for_sale(PieceType::Rook), None, None];
+
+        state.restock();
+
+        let kept = state.pieces_for_sale[0].unwrap();
+        assert_eq!(kept.piece_type, PieceType::Rook);
+        assert!(kept.discounted);
+        for slot in &state.pieces_for_sale[1..] {
+            assert!(!slot.unwrap().discounted);
+        }
+    }
+
+    #[test]
+    fn refill_keeps_unsold_pieces_at_full_price() {
+        let mut state = game(START);
+        state.rules.restock = RestockPolicy::Refill;
+        state.pieces_for_sale = [for_sale(PieceType::Rook), None, None];
+
+        state.restock();
+
+        assert_eq!(state.pieces_for_sale[0], for_sale(PieceType::Rook));
+        assert!(state.pieces_for_sale.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn restock_leaves_slots_past_the_shop_size_empty() {
+        let mut state = game(START);
+        state.rules.shop_size = 2;
+
+        state.restock();
+
+        assert!(state.pieces_for_sale[..2].iter().all(Option::is_some));
+        assert_eq!(state.pieces_for_sale[2], None);
+    }
+
+    #[test]
+    fn ending_a_turn_restocks() {
+        let mut state = game(START);
+        state.pieces_for_sale = [None; SHOP_SIZE];
+
+        state.apply(move_action("e2", "e4"));
+
+        assert!(state.pieces_for_sale.iter().all(Option::is_some));
+    }
+
+    /// Checks the pieces against pleco's board directly, rather than leaning
+    /// on `debug_assert_synced`.
+    fn assert_synced(state: &GameState) {
+        let ours =
+            chess::generate_fen(&state.pieces, state.whos_turn, &state.move_state);
+        let theirs = state.board.pleco().fen();
+        let ours = ours.split_whitespace().take(2).collect::<Vec<_>>();
+        let theirs = theirs.split_whitespace().take(2).collect::<Vec<_>>();
+
+        assert_eq!(ours, theirs);
+    }
+
+    fn assert_castles(fen: &str, king: (&str, &str), rook: (&str, &str)) {
+        let mut state = game(fen);
+        let result = state.apply(move_action(king.0, king.1));
+
+        assert_eq!(
+            result.moved,
+            vec![(square(king.0), square(king.1)), (square(rook.0), square(rook.1))]
+        );
+        assert_eq!(piece_on(&state, king.1).unwrap().piece_type, PieceType::King);
+        assert_eq!(piece_on(&state, rook.1).unwrap().piece_type, PieceType::Rook);
+        assert!(piece_on(&state, king.0).is_none());
+        assert!(piece_on(&state, rook.0).is_none());
+        assert_synced(&state);
+    }
+
+    const CASTLING: &str = "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1";
+    const BLACK_CASTLING: &str = "r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1";
+
+    #[test]
+    fn white_castles_kingside() {
+        assert_castles(CASTLING, ("e1", "g1"), ("h1", "f1"));
+    }
+
+    #[test]
+    fn white_castles_queenside() {
+        assert_castles(CASTLING, ("e1", "c1"), ("a1", "d1"));
+    }
+
+    #[test]
+    fn black_castles_kingside() {
+        assert_castles(BLACK_CASTLING, ("e8", "g8"), ("h8", "f8"));
+    }
+
+    #[test]
+    fn black_castles_queenside() {
+        assert_castles(BLACK_CASTLING, ("e8", "c8"), ("a8", "d8"));
+    }
+
+    #[test]
+    fn bought_rooks_can_castle_in_the_default_game() {
+        let rules = rule_set(&CONFIG, "standard").unwrap();
+        let mut state = GameState::with_rules(&CONFIG, rules, 1);
+        state.white_coins = 20;
+        state.pieces_for_sale[0] = for_sale(PieceType::Rook);
+
+        let result = state.apply(Action::Buy {
+            slot: 0,
+            place: square("h1"),
+        });
+        assert!(!result.is_empty());
+
+        let result = state.apply(move_action("e1", "g1"));
+        assert_eq!(
+            result.moved,
+            vec![(square("e1"), square("g1")), (square("h1"), square("f1"))]
+        );
+        assert_synced(&state);
+    }
+
+    #[test]
+    fn bought_rooks_can_castle_when_kings_are_placed() {
+        let rules = rule_set(&CONFIG, "kings").unwrap();
+        let mut state = GameState::with_rules(&CONFIG, rules, 1);
+        state.white_coins = 20;
+        state.pieces_for_sale[0] = for_sale(PieceType::Rook);
+        state.apply(Action::Buy {
+            slot: 0,
+            place: square("h1"),
+        });
+
+        assert!(state.is_king_placement(square("e1"), square("f1")));
+        assert!(!state.is_king_placement(square("e1"), square("g1")));
+
+        let result = state.apply(move_action("e1", "g1"));
+        assert_eq!(
+            result.moved,
+            vec![(square("e1"), square("g1")), (square("h1"), square("f1"))]
+        );
+        assert_eq!(state.history.last().unwrap().san, Some("O-O".to_owned()));
+        assert_synced(&state);
+    }
+
+    #[test]
+    fn en_passant_removes_the_passed_pawn() {
+        let mut state = game("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1");
+        let result = state.apply(move_action("e5", "d6"));
+
+        assert_eq!(result.removed.len(), 1);
+        assert_eq!(result.removed[0].position, square("d5"));
+        assert!(piece_on(&state, "d5").is_none());
+        assert_eq!(piece_on(&state, "d6").unwrap().piece_type, PieceType::Pawn);
+        assert_eq!(state.pieces.len(), 3);
+        assert_synced(&state);
+    }
+
+    #[test]
+    fn pawns_can_under_promote() {
+        let promotions = [PieceType::Rook, PieceType::Bishop, PieceType::Knight];
+
+        for &promotion in &promotions {
+            let mut state = game("4k3/P7/8/8/8/8/8/4K3 w - - 0 1");
+            let result = state.apply(Action::Move {
+                from: square("a7"),
+                to: square("a8"),
+                promotion: Some(promotion),
+            });
+
+            assert!(!result.is_empty());
+            assert_eq!(piece_on(&state, "a8").unwrap().piece_type, promotion);
+            assert_synced(&state);
+        }
+    }
+
+    #[test]
+    fn san_marks_check_and_mate() {
+        let mut state = game("4k3/8/8/8/8/8/8/R3K3 w - - 0 1");
+        state.apply(move_action("a1", "a8"));
+        assert_eq!(state.history[0].san, Some("Ra8+".to_owned()));
+
+        let mut state = game("6k1/5ppp/8/8/8/8/8/R3K3 w - - 0 1");
+        state.apply(move_action("a1", "a8"));
+        assert_eq!(state.history[0].san, Some("Ra8#".to_owned()));
+    }
+
+    #[test]
+    fn detects_checkmate() {
+        let state =
+            game("rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3");
+
+        assert_eq!(state.detect_outcome(), GameOutcome::Victory(ChessColor::Black));
+        assert_eq!(state.game_outcome, GameOutcome::Victory(ChessColor::Black));
+    }
+
+    #[test]
+    fn detects_stalemate() {
+        let state = game("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1");
+
+        assert_eq!(state.detect_outcome(), GameOutcome::Stalemate);
+    }
+
+    #[test]
+    fn bare_kings_without_coins_are_a_draw() {
+        let mut state = game("4k3/8/8/8/8/8/8/4K3 w - - 0 1");
+
+        assert_eq!(
+            state.detect_outcome(),
+            GameOutcome::Draw(DrawReason::InsufficientMaterial)
+        );
+
+        state.white_coins = chess::cheapest_price(&state.prices);
+        assert_eq!(state.detect_outcome(), GameOutcome::Ongoing);
+    }
+
+    #[test]
+    fn selling_the_last_mobile_piece_can_end_the_game() {
+        let stuck = "k7/8/8/8/8/8/P4q2/7K w - - 0 1";
+
+        let mut state = game(stuck);
+        state.pieces_for_sale = [for_sale(PieceType::Queen); SHOP_SIZE];
+        state.apply(Action::Sell(square("a2")));
+        assert_eq!(state.game_outcome, GameOutcome::Stalemate);
+
+        // Unless the coins are there to buy a way out
+        let mut state = game(stuck);
+        state.pieces_for_sale = [for_sale(PieceType::Queen); SHOP_SIZE];
+        state.white_coins = 5;
+        state.apply(Action::Sell(square("a2")));
+        assert_eq!(state.game_outcome, GameOutcome::Ongoing);
+    }
+
+    #[test]
+    fn starting_position_is_ongoing() {
+        assert_eq!(game(START).detect_outcome(), GameOutcome::Ongoing);
+    }
+
+    #[test]
+    fn fifty_quiet_moves_are_a_draw() {
+        let fen = "4k3/8/8/8/8/8/8/R3K3 w - - 98 60";
+
+        let mut state = game(fen);
+        state.apply(move_action("a1", "a2"));
+        assert_eq!(state.move_state.halfmove_clock, 99);
+        assert_eq!(state.game_outcome, GameOutcome::Ongoing);
+
+        state.apply(move_action("e8", "d8"));
+        assert_eq!(state.move_state.halfmove_clock, 100);
+        assert_eq!(state.game_outcome, GameOutcome::Draw(DrawReason::FiftyMoves));
+    }
+
+    #[test]
+    fn threefold_repetition_is_a_draw() {
+        let mut state = game("r3k3/8/8/8/8/8/8/R3K3 w - - 0 1");
+        let shuffle = [("a1", "a2"), ("a8", "a7"), ("a2", "a1"), ("a7", "a8")];
+
+        for &(from, to) in &shuffle {
+            state.apply(move_action(from, to));
+        }
+        assert_eq!(state.game_outcome, GameOutcome::Ongoing);
+
+        for &(from, to) in &shuffle {
+            state.apply(move_action(from, to));
+        }
+        assert_eq!(state.game_outcome, GameOutcome::Draw(DrawReason::Repetition));
+    }
+
+    #[test]
+    fn nothing_but_social_actions_once_the_game_is_over() {
+        let mut state = game(START);
+        state.white_coins = 20;
+        state.pieces_for_sale[0] = for_sale(PieceType::Knight);
+        state.apply(Action::Resign(ChessColor::White));
+
+        let refused = [
+            move_action("e2", "e4"),
+            Action::Sell(square("a2")),
+            Action::Buy {
+                slot: 0,
+                place: square("b1"),
+            },
+        ];
+
+        for &action in &refused {
+            assert!(state.apply(action).is_empty(), "{:?} went ahead", action);
+        }
+        assert_eq!(piece_on(&state, "e2").unwrap().piece_type, PieceType::Pawn);
+        assert_eq!(state.white_coins, 20);
+        assert!(state.history.is_empty());
+        assert_eq!(state.game_outcome, GameOutcome::Resigned(ChessColor::White));
+    }
+
+    #[test]
+    fn ending_a_turn_keeps_an_outcome_already_reached() {
+        let mut state = game(START);
+        state.apply(move_action("e2", "e4"));
+        state.flag(ChessColor::Black);
+
+        state.end_turn();
+        assert_eq!(state.game_outcome, GameOutcome::OutOfTime(ChessColor::Black));
+    }
+}
@@ -19,6 +19,23 @@ pub struct Mesh {
 }
 
 
+pub struct RenderCommand<'a> {
+    pub mesh: &'a Mesh,
+    pub color: Vec4<f32>,
+    pub mvp_matrix: Mat4<f32>,
+    pub colormap: &'a SrgbTexture2d,
+    pub texture_scale: Vec3<f32>,
+    pub texture_offset: Vec3<f32>,
+}
+
+pub struct UiRenderCommand<'a> {
+    pub colormap: &'a SrgbTexture2d,
+    pub pos: Vec3<f32>,
+    pub scale: f32,
+    pub angle: f32,
+}
+
+
 pub fn create_shader(display: &Display, shader_source: &str) -> Program {
     use glium::program::ProgramCreationInput;
 
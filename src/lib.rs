@@ -3,14 +3,28 @@
 #[macro_use]
 extern crate serde_derive;
 
-#[cfg(debug_assertions)]
-extern crate toml;
-
 extern crate okmath;
 extern crate image;
+extern crate pleco;
+extern crate pleco_engine;
+extern crate rand;
+extern crate toml;
 
+pub mod ai;
+pub mod board;
+pub mod chess;
+pub mod clock;
 pub mod config;
+pub mod data;
+pub mod engine;
+pub mod game;
 mod gen_config;
+pub mod net;
+pub mod pgn;
+pub mod replay;
+pub mod rng;
+pub mod save;
+pub mod undo;
 
 use std::time::Instant;
 
@@ -9,15 +9,10 @@ extern crate glium;
 
 extern crate okmath;
 extern crate glium_text;
-extern crate pleco;
-extern crate pleco_engine;
-extern crate rand;
 extern crate rodio;
 extern crate wavefront_obj;
 
 mod audio;
-mod chess;
-mod data;
 mod graphics;
 mod input;
 mod ui;
@@ -28,41 +23,21 @@ use okmath::*;
 use glium::{glutin::EventsLoop, Display};
 use rodio::Device;
 
-use chessjam::config::Config;
-use data::*;
+use chessjam::{
+    ai::{self, AiController},
+    chess,
+    clock::{self, Clock},
+    config::{self, Config},
+    data::*,
+    engine::UciEngine,
+    game::{self, ActionResult, GameState},
+    net::{self, Client, Host, NetGame},
+    rng::{self, GameRng},
+    undo::UndoHistory,
+};
+use graphics::{RenderCommand, UiRenderCommand};
 use input::*;
-
-
-fn random_piece(config: &Config) -> PieceType {
-    use rand::distributions::{IndependentSample, Weighted, WeightedChoice};
-
-    let mut choices = [
-        Weighted {
-            weight: config.weights.pawn as u32,
-            item: PieceType::Pawn,
-        },
-        Weighted {
-            weight: config.weights.knight as u32,
-            item: PieceType::Knight,
-        },
-        Weighted {
-            weight: config.weights.rook as u32,
-            item: PieceType::Rook,
-        },
-        Weighted {
-            weight: config.weights.bishop as u32,
-            item: PieceType::Bishop,
-        },
-        Weighted {
-            weight: config.weights.queen as u32,
-            item: PieceType::Queen,
-        },
-    ];
-    let wc = WeightedChoice::new(&mut choices);
-    let mut rng = rand::thread_rng();
-
-    wc.ind_sample(&mut rng)
-}
+use ui::{ControlState, GameButton};
 
 
 #[allow(unused_variables)]
@@ -83,14 +58,16 @@ fn stopclock(title: &str, last_tick: &mut Instant, buffer: &mut String) {
 fn main() {
     use glium::glutin::{Api, ContextBuilder, GlProfile, GlRequest, WindowBuilder};
 
-    let config = chessjam::config::load_config();
-    let res = &config.graphics.resolution;
+    let mut config = config::load_config();
 
     let mut events_loop = EventsLoop::new();
 
-    let window = WindowBuilder::new()
-        .with_dimensions(res[0] as u32, res[1] as u32)
-        .with_title("Purchess");
+    let window = {
+        let res = &config.graphics.resolution;
+        WindowBuilder::new()
+            .with_dimensions(res[0] as u32, res[1] as u32)
+            .with_title("Purchess")
+    };
 
     let context = {
         ContextBuilder::new()
@@ -105,21 +82,109 @@ fn main() {
 
     let speaker = rodio::default_output_device().unwrap();
 
+    let mut options = options_from_args();
+
     loop {
-        let rerun = run_game(display, &mut events_loop, &speaker);
+        let rerun =
+            run_game(display, &mut events_loop, &speaker, &config, options.take());
 
         if !rerun {
             break;
         }
+
+        config = config::reload_config(config);
     }
 }
 
 
+/// Choices made on the command line. `load_path` and `replay_path` are what
+/// to open on the first run, instead of a new game, and `seed` is what the
+/// first new game is seeded with. `time_control` names the clock new games
+/// are played with. `host` and `connect` start a network game
+/// on the first run too: hosting on a port as white, or joining an address,
+/// just to watch if `spectate` is set.
+#[derive(Debug, Default)]
+struct LaunchOptions {
+    load_path: Option<String>,
+    replay_path: Option<String>,
+    white_player: Option<PlayerKind>,
+    black_player: Option<PlayerKind>,
+    difficulty: Option<String>,
+    rules: Option<String>,
+    time_control: Option<String>,
+    seed: Option<u64>,
+    host: Option<u16>,
+    connect: Option<String>,
+    spectate: bool,
+}
+
+impl LaunchOptions {
+    /// Restarting always begins a fresh game, so files are only opened once.
+    fn take(&mut self) -> LaunchOptions {
+        LaunchOptions {
+            load_path: self.load_path.take(),
+            replay_path: self.replay_path.take(),
+            white_player: self.white_player,
+            black_player: self.black_player,
+            difficulty: self.difficulty.clone(),
+            rules: self.rules.clone(),
+            time_control: self.time_control.clone(),
+            seed: self.seed.take(),
+            host: self.host.take(),
+            connect: self.connect.take(),
+            spectate: self.spectate,
+        }
+    }
+}
+
+fn options_from_args() -> LaunchOptions {
+    let mut options = LaunchOptions::default();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--load" => options.load_path = args.next(),
+            "--replay" => options.replay_path = args.next(),
+            "--white" => {
+                options.white_player =
+                    args.next().and_then(|name| game::parse_player_kind(&name))
+            }
+            "--black" => {
+                options.black_player =
+                    args.next().and_then(|name| game::parse_player_kind(&name))
+            }
+            "--difficulty" => options.difficulty = args.next(),
+            "--rules" => options.rules = args.next(),
+            "--clock" => options.time_control = args.next(),
+            "--seed" => {
+                options.seed = args.next().and_then(|seed| rng::parse_seed(&seed))
+            }
+            "--host" => {
+                options.host = args
+                    .next()
+                    .and_then(|port| port.parse().ok())
+                    .or(Some(net::DEFAULT_PORT))
+            }
+            "--connect" => options.connect = args.next(),
+            "--watch" => {
+                options.connect = args.next();
+                options.spectate = true;
+            }
+            _ => (),
+        }
+    }
+
+    options
+}
+
+
 #[allow(cyclomatic_complexity)]
 fn run_game(
     display: &Display,
     events_loop: &mut EventsLoop,
     speaker: &Device,
+    config: &Config,
+    options: LaunchOptions,
 ) -> bool {
     use std::io::Cursor;
 
@@ -128,8 +193,6 @@ fn run_game(
 
     use ui::LabelRenderer;
 
-    let config = chessjam::config::load_config();
-
     let [model_shader, shadow_shader, ui_shader, skyball_shader] = resource_str!([
         "assets/shaders/model.glsl",
         "assets/shaders/shadow.glsl",
@@ -256,75 +319,129 @@ fn run_game(
         .map(|&slice| Vec2(slice))
         .collect::<Vec<Vec2<i32>>>();
 
-    let mut pieces = {
-        let mut pieces = Vec::new();
-        for x in 0..8 {
-            pieces.push(Piece {
-                position: vec2(x, 1),
-                color: ChessColor::White,
-                piece_type: PieceType::Pawn,
-                moved: false,
-                animation: None,
-                delete_after_animation: false,
-            });
-            pieces.push(Piece {
-                position: vec2(x, 6),
-                color: ChessColor::Black,
-                piece_type: PieceType::Pawn,
-                moved: false,
-                animation: None,
-                delete_after_animation: false,
-            });
-        }
-        pieces.push(Piece {
-            position: vec2(4, 0),
-            color: ChessColor::White,
-            piece_type: PieceType::King,
-            moved: false,
-            animation: None,
-            delete_after_animation: false,
-        });
-        pieces.push(Piece {
-            position: vec2(4, 7),
-            color: ChessColor::Black,
-            piece_type: PieceType::King,
-            moved: false,
-            animation: None,
-            delete_after_animation: false,
-        });
-        pieces
-    };
+    let save_file = config.game.save_file.to_string();
+    let pgn_file = config.game.pgn_file.to_string();
 
-    let mut pieces_for_sale = [
-        Some(PieceForSale {
-            piece_type: random_piece(&config),
-            discounted: false,
-        }),
-        Some(PieceForSale {
-            piece_type: random_piece(&config),
-            discounted: false,
-        }),
-        Some(PieceForSale {
-            piece_type: random_piece(&config),
-            discounted: false,
+    let mut replay = options.replay_path.and_then(|path| {
+        chessjam::replay::load_replay(&path, &config)
+            .map_err(|err| eprintln!("Failed to replay {}: {}", path, err))
+            .ok()
+    });
+
+    let rules = match options.rules {
+        Some(ref name) => game::rule_set(&config, name).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            game::rules(&config)
         }),
-    ];
+        None => game::rules(&config),
+    };
+    let seed = options
+        .seed
+        .or_else(|| game::config_seed(&config))
+        .unwrap_or_else(|| GameRng::random().seed());
+    let time_control = match options.time_control {
+        Some(ref name) => game::time_control_preset(&config, name)
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                game::time_control(&config)
+            }),
+        None => game::time_control(&config),
+    };
+    let new_game = || {
+        let mut state = GameState::with_rules(&config, rules.clone(), seed);
+        state.clock = time_control.clone().map(Clock::new);
+        state
+    };
+    let mut state = match (&replay, options.load_path) {
+        (&Some(ref replay), _) => replay.state().clone(),
+        (&None, Some(path)) => chessjam::save::load_game(&path, &config)
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to load {}: {}", path, err);
+                new_game()
+            }),
+        (&None, None) => new_game(),
+    };
+    let mut net = match (&replay, options.host, options.connect) {
+        (&Some(_), _, _) => None,
+        (&None, Some(port), _) => match Host::bind(
+            ("0.0.0.0", port),
+            ChessColor::White,
+        ) {
+            Ok(host) => {
+                println!("Hosting on port {}", port);
+                Some(NetGame::Host(host))
+            }
+            Err(err) => {
+                eprintln!("Failed to host on port {}: {}", port, err);
+                None
+            }
+        },
+        (&None, None, Some(address)) => {
+            let joined = if options.spectate {
+                Client::watch(&*address, &config)
+            }
+            else {
+                Client::connect(&*address, &config)
+            };
 
-    let mut white_coins = 0;
-    let mut black_coins = 0;
-    let mut game_outcome = GameOutcome::Ongoing;
+            match joined {
+                Ok((client, joined)) => {
+                    state = joined;
+                    Some(NetGame::Client(client))
+                }
+                Err(err) => {
+                    eprintln!("Failed to join {}: {}", address, err);
+                    None
+                }
+            }
+        }
+        (&None, None, None) => None,
+    };
+    println!("Seed {}", state.rng.seed());
+    let mut undo_history = UndoHistory::new();
+    let undo_ai_turns = config.game.undo_ai_turns;
+    let mut departing_pieces: Vec<Piece> = Vec::new();
 
     let mut control_state = ControlState::Idle;
-    let mut valid_destinations: Vec<Vec2<i32>> = vec![];
-    let mut whos_turn = ChessColor::White;
-    let ai_player = Some(ChessColor::Black);
-    let mut ai_pawns_to_sell = {
-        use rand::distributions::{IndependentSample, Range};
-
-        let between = Range::new(4, 8);
-        let mut rng = rand::thread_rng();
-        between.ind_sample(&mut rng)
+    let mut ai = match config.ai.engine.to_string() {
+        ref path if path.is_empty() => AiController::new(),
+        path => match UciEngine::new(&path) {
+            Ok(engine) => AiController::with_engine(Box::new(engine)),
+            Err(err) => {
+                eprintln!("{}", err);
+                AiController::new()
+            }
+        },
     };
+    let mut valid_destinations: Vec<Vec2<i32>> = vec![];
+    let mut suggested_placement: Option<Vec2<i32>> = None;
+    if let Some(kind) = options.white_player {
+        state.players.white = kind;
+    }
+    if let Some(kind) = options.black_player {
+        state.players.black = kind;
+    }
+    if let Some(name) = options.difficulty {
+        match game::difficulty(&config, &name) {
+            Some(difficulty) => state.difficulty = difficulty,
+            None => eprintln!("Unknown difficulty {}", name),
+        }
+    }
+    if net.is_some() {
+        state.players = Players {
+            white: PlayerKind::Human,
+            black: PlayerKind::Human,
+        };
+    }
+
+    const CAMERA_TURN_SPEED: f32 = 240.0;
+    const PLACEMENT_HINT_DEPTH: u16 = 2;
+    let mut follow_turn = config.camera.follow_turn;
+    let mut camera_side = viewing_side(&state, &net, follow_turn);
+    let mut camera_turn: f32 = 0.0;
+    if camera_side == ChessColor::Black {
+        camera_angle += 180.0;
+    }
 
     let mut lit_render_buffer = Vec::new();
     let mut highlight_render_buffer = Vec::new();
@@ -412,11 +529,127 @@ fn run_game(
             std::process::exit(0);
         }
         if keyboard.pressed(Key::R) && keyboard.modifiers.logo {
+            ai.cancel();
             return true;
         }
+        if let Some(ref mut net) = net {
+            net.update(&state);
+        }
+
+        if keyboard.pressed(Key::M) && net.is_none() {
+            ai.cancel();
+            state.players = next_players(state.players);
+            control_state = ControlState::Idle;
+            valid_destinations.clear();
+        }
+        if keyboard.pressed(Key::D) && net.is_none() {
+            ai.cancel();
+            let next = game::DIFFICULTIES
+                .iter()
+                .position(|&name| name == state.difficulty.name)
+                .map_or(0, |index| (index + 1) % game::DIFFICULTIES.len());
+            if let Some(difficulty) =
+                game::difficulty(&config, game::DIFFICULTIES[next])
+            {
+                state.difficulty = difficulty;
+            }
+        }
+        if keyboard.pressed(Key::C) {
+            follow_turn = !follow_turn;
+        }
         if keyboard.pressed(Key::H) {
             show_stats = !show_stats;
         }
+        if keyboard.pressed(Key::S) && keyboard.modifiers.logo {
+            if let Err(err) = chessjam::save::save_game(&save_file, &state) {
+                eprintln!("Failed to save {}: {}", save_file, err);
+            }
+        }
+        if keyboard.pressed(Key::E) && keyboard.modifiers.logo {
+            if let Err(err) = chessjam::pgn::save_pgn(&pgn_file, &state) {
+                eprintln!("Failed to export {}: {}", pgn_file, err);
+            }
+        }
+        if keyboard.pressed(Key::L) && keyboard.modifiers.logo && net.is_none() {
+            match chessjam::save::load_game(&save_file, &config) {
+                Ok(loaded) => {
+                    ai.cancel();
+                    state = loaded;
+                    println!("Seed {}", state.rng.seed());
+                    undo_history.clear();
+                    replay = None;
+                    departing_pieces.clear();
+                    control_state = ControlState::Idle;
+                    valid_destinations.clear();
+                    game_end_time = None;
+                    music.set_volume(1.0);
+                }
+                Err(err) => eprintln!("Failed to load {}: {}", save_file, err),
+            }
+        }
+
+        if keyboard.pressed(Key::Z)
+            && keyboard.modifiers.logo
+            && replay.is_none()
+            && net.is_none()
+        {
+            ai.cancel();
+            let players = state.players;
+            let difficulty = state.difficulty.clone();
+            let clock = state.clock.clone();
+
+            // Against the AI, its half-turns are skipped over so that undoing
+            // gives the human their own last action back
+            let skip_ai = undo_ai_turns && players.white != players.black;
+            let skip = |color: ChessColor| skip_ai && players.is_ai(color);
+
+            if keyboard.modifiers.shift {
+                if let Some(result) = undo_history.redo_turn(&mut state, skip) {
+                    departing_pieces.clear();
+                    animate_result(
+                        &mut state,
+                        &mut departing_pieces,
+                        result,
+                        &buy_tiles,
+                        sell_tile,
+                    );
+                }
+            }
+            else if let Some((undone, result)) =
+                undo_history.undo_turn(&mut state, skip)
+            {
+                departing_pieces.clear();
+                animate_undo(
+                    &mut state,
+                    &undone,
+                    &mut departing_pieces,
+                    result,
+                    &buy_tiles,
+                    sell_tile,
+                );
+            }
+
+            // Undoing takes back actions, not changes to who's playing - nor
+            // the time spent, or it'd be a way to get more
+            state.players = players;
+            state.difficulty = difficulty;
+            state.clock = clock;
+            control_state = ControlState::Idle;
+            valid_destinations.clear();
+            if state.game_outcome == GameOutcome::Ongoing {
+                game_end_time = None;
+                music.set_volume(1.0);
+            }
+        }
+
+        let side = viewing_side(&state, &net, follow_turn);
+        if side != camera_side {
+            camera_side = side;
+            camera_turn += if camera_turn > 0.0 { -180.0 } else { 180.0 };
+        }
+        let turn_step = (CAMERA_TURN_SPEED * dt).min(camera_turn.abs());
+        camera_angle += turn_step * camera_turn.signum();
+        camera_turn -= turn_step * camera_turn.signum();
 
         camera_angle += camera_motion.0[0] * dt;
         camera_tilt += camera_motion.0[1] * dt;
@@ -496,6 +729,34 @@ fn run_game(
             chessjam::world_to_grid(hit)
         };
 
+        // In the same units as labels
+        let ui_cursor = {
+            let (w, h) = display.get_framebuffer_dimensions();
+            let mouse_pos = (Vec2(mouse.position()) / vec2(w, h).as_f64()).as_f32();
+            let screen_pos = (mouse_pos - vec2(0.5, 0.5)) * vec2(2.0, -2.0);
+            let stretch =
+                chessjam::viewport_stretch((w, h), viewport.width, viewport.height);
+            screen_pos * stretch * vec2(4.5 * TARGET_ASPECT, 4.5)
+        };
+
+        // Resigning, offers and answers, for whichever sides are played here
+        let mut game_buttons: Vec<(ChessColor, GameButton, Vec2<f32>)> = Vec::new();
+        if state.game_outcome == GameOutcome::Ongoing && replay.is_none() {
+            for &color in &[ChessColor::White, ChessColor::Black] {
+                let played_here = match net {
+                    Some(ref net) => net.color() == Some(color),
+                    None => !state.players.is_ai(color),
+                };
+
+                if played_here {
+                    for (button, pos) in ui::game_buttons(&state, color) {
+                        game_buttons.push((color, button, pos));
+                    }
+                }
+            }
+        }
+        let hovered_button = ui::button_at(&game_buttons, ui_cursor);
+
         stopclock("pre-update", timer, stats_text);
 
         let mut valid_purchase_placements = Vec::new();
@@ -503,33 +764,34 @@ fn run_game(
 
         // update
         {
-            use pleco::Board;
-
             match control_state {
                 ControlState::SelectedPieceIndex(index) => {
-                    can_sell = pieces[index].piece_type != PieceType::King;
+                    can_sell = state.pieces[index].piece_type != PieceType::King;
                 }
                 ControlState::SelectedPurchaseIndex(index) => {
-                    let piece_for_sale = pieces_for_sale[index];
+                    let piece_for_sale = state.pieces_for_sale[index];
 
                     if let Some(piece_for_sale) = piece_for_sale {
                         valid_purchase_placements =
                             chess::valid_purchase_placements(
-                                &pieces,
+                                &state.pieces,
                                 piece_for_sale.piece_type,
-                                whos_turn,
+                                state.whos_turn,
                             );
                     }
                 }
-                ControlState::Idle => (),
+                ControlState::PendingPromotion { .. } | ControlState::Idle => (),
             }
 
             // animation
             let animations_playing = {
                 let mut animating = false;
-                let mut trash = Vec::new();
 
-                for (index, piece) in pieces.iter_mut().enumerate() {
+                for piece in state
+                    .pieces
+                    .iter_mut()
+                    .chain(departing_pieces.iter_mut())
+                {
                     let mut anim_done = false;
 
                     if let Some(ref mut anim) = piece.animation {
@@ -544,7 +806,6 @@ fn run_game(
                         piece.animation = None;
 
                         if piece.delete_after_animation {
-                            trash.push(index);
                             audio::play_sound(speaker, &coin_sound);
                         }
                         else {
@@ -553,103 +814,120 @@ fn run_game(
                     }
                 }
 
-                trash.reverse();
-                for index in trash {
-                    pieces.swap_remove(index);
-                }
+                departing_pieces.retain(|piece| piece.animation.is_some());
 
                 animating
             };
 
             stopclock("animation", timer, stats_text);
 
+            // Clocks only run while there's nothing left to watch
+            let clock_running = replay.is_none()
+                && !animations_playing
+                && net.as_ref().map_or(true, NetGame::is_connected);
 
-            // Player actions
-            let mut player_move = None;
-            let mut piece_promotion = None;
-            let mut piece_to_sell = None;
-            let mut player_purchase = None;
+            if clock_running {
+                let authority = net.as_ref().map_or(true, NetGame::is_host);
 
-            let allow_player_actions =
-                game_outcome == GameOutcome::Ongoing && !animations_playing;
+                if let Some(color) = state.tick_clock(dt) {
+                    if authority {
+                        ai.cancel();
+                        state.flag(color);
+                        if let Some(ref mut net) = net {
+                            net.flag(color);
+                        }
+                    }
+                }
+            }
 
-            if allow_player_actions {
-                if ai_player == Some(whos_turn) {
-                    if ai_pawns_to_sell > 0 {
-                        let pawns = pieces
-                            .iter()
-                            .enumerate()
-                            .filter(|(_, p)| {
-                                p.color == whos_turn
-                                    && p.piece_type == PieceType::Pawn
-                            })
-                            .map(|(i, _)| i)
-                            .collect::<Vec<usize>>();
-
-                        let mut rng = rand::thread_rng();
-                        let index = rand::seq::sample_slice(&mut rng, &pawns, 1)[0];
-                        piece_to_sell = Some(index);
-                        ai_pawns_to_sell -= 1;
+            if let Some(color) = net.as_mut().and_then(|net| net.take_flag()) {
+                state.flag(color);
+            }
+
+
+            // Replay
+            if let Some(ref mut replay) = replay {
+                if keyboard.pressed(Key::Right) {
+                    if let Some(result) = replay.step_forward() {
+                        state = replay.state().clone();
+                        departing_pieces.clear();
+                        animate_result(
+                            &mut state,
+                            &mut departing_pieces,
+                            result,
+                            &buy_tiles,
+                            sell_tile,
+                        );
+                    }
+                }
+                if keyboard.pressed(Key::Left) {
+                    if let Some(result) = replay.step_back() {
+                        let undone = state.clone();
+                        state = replay.state().clone();
+                        departing_pieces.clear();
+                        animate_undo(
+                            &mut state,
+                            &undone,
+                            &mut departing_pieces,
+                            result,
+                            &buy_tiles,
+                            sell_tile,
+                        );
+                    }
+                }
+            }
+
+            // Player actions
+            let mut action = None;
+            let mut from_network = false;
+
+            let allow_player_actions = state.game_outcome == GameOutcome::Ongoing
+                && !animations_playing
+                && replay.is_none();
+            let local_turn = net.as_ref().map_or(true, |net| net.can_act(&state));
+
+            // Offers made to the AI are answered straight away
+            let answered_by_ai = match state.offer {
+                Some(pending) if net.is_none() => {
+                    let color = game::opponent(pending.from);
+                    if state.players.is_ai(color) {
+                        Some(color)
                     }
                     else {
-                        let coins = match whos_turn {
-                            ChessColor::Black => black_coins,
-                            ChessColor::White => white_coins,
-                        };
+                        None
+                    }
+                }
+                _ => None,
+            };
 
-                        let best_purchase = pieces_for_sale
-                            .iter()
-                            .enumerate()
-                            .filter_map(|(index, piece)| piece.map(|p| (index, p)))
-                            .map(|(index, piece)| {
-                                (index, piece.piece_type, chess::buy_price(piece))
-                            })
-                            .filter(|(_, _, price)| *price <= coins)
-                            .max_by_key(|(_, piece, _)| *piece)
-                            .map(|(index, _, _)| index);
-
-                        if let Some(index) = best_purchase {
-                            let piece_for_sale = pieces_for_sale[index].unwrap();
-                            let valid_purchase_placements =
-                                chess::valid_purchase_placements(
-                                    &pieces,
-                                    piece_for_sale.piece_type,
-                                    whos_turn,
-                                );
-                            let mut rng = rand::thread_rng();
-                            let place = rand::seq::sample_slice(
-                                &mut rng,
-                                &valid_purchase_placements,
-                                1,
-                            )[0];
-                            player_purchase = Some((index, place));
-                        }
-                        else {
-                            let mov = chess::decide_move(&pieces, whos_turn);
-                            let from = chessjam::grid_from_u8(mov.get_src_u8());
-                            let to = chessjam::grid_from_u8(mov.get_dest_u8());
-                            player_move = Some((from, to));
-
-                            if mov.is_promo() {
-                                use pleco::PieceType::*;
-
-                                let piece = match mov.promo_piece() {
-                                    Q => PieceType::Queen,
-                                    R => PieceType::Rook,
-                                    B => PieceType::Bishop,
-                                    N => PieceType::Knight,
-                                    P => PieceType::Pawn,
-                                    _ => unreachable!(
-                                        "Invalid promotion was attempted."
-                                    ),
-                                };
-
-                                piece_promotion = Some(piece);
-                            }
-                        }
+            if allow_player_actions {
+                if let Some(ref mut net) = net {
+                    action = net.poll(&state);
+                    from_network = action.is_some();
+                }
+
+                if from_network {
+                    control_state = ControlState::Idle;
+                    valid_destinations.clear();
+                }
+                else if let (Some((color, button)), true) =
+                    (hovered_button, mouse.pressed(Button::Left))
+                {
+                    action = Some(button.action(color));
+                    control_state = ControlState::Idle;
+                    valid_destinations.clear();
+                }
+                else if let Some(color) = answered_by_ai {
+                    let accept = ai::accepts_offer(&state, color);
+                    action = Some(Action::Answer(color, accept));
+                }
+                else if state.players.is_ai(state.whos_turn) {
+                    if !ai.is_thinking() {
+                        ai.start(&state);
                     }
+                    action = ai.poll(&state);
                 }
-                else if mouse.pressed(Button::Left) {
+                else if mouse.pressed(Button::Left) && local_turn {
                     match control_state {
                         ControlState::Idle => {
                             for (index, &tile) in buy_tiles.iter().enumerate() {
@@ -660,34 +938,65 @@ fn run_game(
                             }
                             control_state = match chess::piece_at(
                                 tile_cursor,
-                                &pieces,
+                                &state.pieces,
                             ) {
-                                Some(index) if pieces[index].color == whos_turn => {
+                                Some(index)
+                                    if state.pieces[index].color
+                                        == state.whos_turn =>
+                                {
                                     ControlState::SelectedPieceIndex(index)
                                 }
                                 _ => control_state,
                             };
                         }
                         ControlState::SelectedPieceIndex(index) => {
+                            let position = state.pieces[index].position;
+                            control_state = ControlState::Idle;
+
                             if valid_destinations.contains(&tile_cursor) {
-                                let from_tile = pieces[index].position;
-                                let to_tile = tile_cursor;
-                                player_move = Some((from_tile, to_tile));
+                                if state.is_promotion(position, tile_cursor) {
+                                    control_state = ControlState::PendingPromotion {
+                                        from: position,
+                                        to: tile_cursor,
+                                    };
+                                }
+                                else {
+                                    action = Some(Action::Move {
+                                        from: position,
+                                        to: tile_cursor,
+                                        promotion: None,
+                                    });
+                                }
                             }
-                            else if tile_cursor == sell_tile && can_sell
-                                && pieces[index].color == whos_turn
-                            {
-                                piece_to_sell = Some(index);
+                            else if tile_cursor == sell_tile && can_sell {
+                                action = Some(Action::Sell(position));
                             }
-                            control_state = ControlState::Idle;
                         }
                         ControlState::SelectedPurchaseIndex(index) => {
-                            let piece_type = pieces_for_sale[index];
+                            let piece_type = state.pieces_for_sale[index];
 
                             if piece_type.is_some() {
                                 if valid_purchase_placements.contains(&tile_cursor)
                                 {
-                                    player_purchase = Some((index, tile_cursor));
+                                    action = Some(Action::Buy {
+                                        slot: index,
+                                        place: tile_cursor,
+                                    });
+                                }
+                            }
+
+                            control_state = ControlState::Idle;
+                        }
+                        ControlState::PendingPromotion { from, to } => {
+                            let choices = ui::promotion_choices(to);
+
+                            for &(tile, piece_type) in &choices {
+                                if tile == tile_cursor {
+                                    action = Some(Action::Move {
+                                        from,
+                                        to,
+                                        promotion: Some(piece_type),
+                                    });
                                 }
                             }
 
@@ -699,157 +1008,75 @@ fn run_game(
                     // TODO(***realname***): Put this at an outer scope, invalidate it safely
                     valid_destinations.clear();
                     if let ControlState::SelectedPieceIndex(index) = control_state {
-                        let piece = &pieces[index];
-                        let (px, py) = piece.position.as_tuple();
-                        let piece_pos_u8 = (py * 8 + px) as u8;
-
-                        let fen = chess::generate_fen(&pieces, whos_turn);
-                        let board = Board::from_fen(&fen).unwrap();
-                        let moves = board.generate_moves();
-
-                        for chessmove in moves.iter() {
-                            let from_u8 = chessmove.get_src_u8();
-                            let to_u8 = chessmove.get_dest_u8();
-                            let dest = chessjam::grid_from_u8(to_u8);
-                            if from_u8 == piece_pos_u8 {
-                                valid_destinations.push(dest);
-                            }
-                        }
-                    }
-                }
-            }
-
-            if let Some((from, to)) = player_move {
-                {
-                    let moved_index = chess::piece_at(from, &pieces).unwrap();
-                    let taken_index = chess::piece_at(to, &pieces);
-                    pieces[moved_index].position = to;
-                    pieces[moved_index].moved = true;
-                    pieces[moved_index].animation = Some(Animation {
-                        from,
-                        to,
-                        t: 0.0,
-                    });
-
-                    if pieces[moved_index].piece_type == PieceType::Pawn
-                        && (to.0[1] == 0 || to.0[1] == 7)
-                    {
-                        pieces[moved_index].piece_type = PieceType::Queen;
-                    }
-
-                    if let Some(promotion) = piece_promotion {
-                        pieces[moved_index].piece_type = promotion;
+                        valid_destinations =
+                            state.valid_destinations(state.pieces[index].position);
                     }
 
-                    if let Some(index) = taken_index {
-                        if pieces[index].color == whos_turn {
-                            // This must be a castle
-                            pieces[index].position = from;
-                            pieces[index].moved = true;
-                            pieces[index].animation = Some(Animation {
-                                from: to,
-                                to: from,
-                                t: 0.0,
-                            });
-                        }
-                        else {
-                            let refund = chess::sell_price(
-                                pieces[index].piece_type,
-                                pieces[index].moved,
-                            );
-
-                            pieces[index].animation = Some(Animation {
-                                from: to,
-                                to: sell_tile,
-                                t: 0.0,
-                            });
-                            pieces[index].position = sell_tile;
-                            pieces[index].delete_after_animation = true;
-
-                            match pieces[index].color {
-                                ChessColor::White => white_coins += refund,
-                                ChessColor::Black => black_coins += refund,
-                            }
+                    suggested_placement = match control_state {
+                        ControlState::SelectedPurchaseIndex(slot)
+                            if config.game.placement_hints =>
+                        {
+                            state.suggested_placement(slot, PLACEMENT_HINT_DEPTH)
                         }
-                    }
+                        _ => None,
+                    };
                 }
+            }
 
-                let prev_turn = whos_turn;
-                whos_turn = match whos_turn {
-                    ChessColor::White => ChessColor::Black,
-                    ChessColor::Black => ChessColor::White,
-                };
-
-                let fen = chess::generate_fen(&pieces, whos_turn);
-                let board = Board::from_fen(&fen).unwrap();
+            // A client's own actions only count once the host sends them back
+            let submit_to_host = net
+                .as_ref()
+                .map_or(false, |net| !net.is_host() && !from_network);
 
-                if board.checkmate() {
-                    game_outcome = GameOutcome::Victory(prev_turn);
-                    game_end_time = Some(Instant::now());
-                }
-                else if board.stalemate() {
-                    game_outcome = GameOutcome::Stalemate;
+            if let Some(action) = action {
+                if submit_to_host {
+                    if let Some(ref mut net) = net {
+                        net.submit(action);
+                    }
                 }
-
-                // Restock shop
-                for piece in &mut pieces_for_sale {
-                    match piece {
-                        Some(piece) => piece.discounted = true,
-                        None => {
-                            *piece = Some(PieceForSale {
-                                piece_type: random_piece(&config),
-                                discounted: false,
-                            });
+                else {
+                    let before = state.clone();
+                    let offer = state.offer;
+                    let result = state.apply(action);
+                    if !result.is_empty() {
+                        match net {
+                            Some(ref mut net) => net.played(&mut state, action),
+                            None => undo_history.record(before, action),
                         }
                     }
-                }
-            }
 
-            if let Some(index) = piece_to_sell {
-                let refund = chess::sell_price(
-                    pieces[index].piece_type,
-                    pieces[index].moved,
-                );
+                    // A take-back can leave anything selected or thought about
+                    // stale
+                    let took_back = match (action, offer) {
+                        (Action::Answer(_, true), Some(pending)) => {
+                            pending.offer == Offer::TakeBack && !result.is_empty()
+                        }
+                        _ => false,
+                    };
 
-                pieces[index].animation = Some(Animation {
-                    from: pieces[index].position,
-                    to: sell_tile,
-                    t: 0.0,
-                });
-                pieces[index].position = sell_tile;
-                pieces[index].delete_after_animation = true;
+                    if took_back {
+                        undo_history.clear();
+                        ai.cancel();
+                        control_state = ControlState::Idle;
+                        valid_destinations.clear();
+                        departing_pieces.clear();
+                    }
 
-                match whos_turn {
-                    ChessColor::White => white_coins += refund,
-                    ChessColor::Black => black_coins += refund,
+                    animate_result(
+                        &mut state,
+                        &mut departing_pieces,
+                        result,
+                        &buy_tiles,
+                        sell_tile,
+                    );
                 }
             }
 
-            if let Some((index, place)) = player_purchase {
-                let piece_for_sale = pieces_for_sale[index].unwrap();
-
-                let price = chess::buy_price(piece_for_sale);
-
-                let wallet = match whos_turn {
-                    ChessColor::White => &mut white_coins,
-                    ChessColor::Black => &mut black_coins,
-                };
-                if price <= *wallet {
-                    *wallet -= price;
-                    pieces.push(Piece {
-                        position: place,
-                        color: whos_turn,
-                        piece_type: piece_for_sale.piece_type,
-                        moved: false,
-                        animation: Some(Animation {
-                            from: buy_tiles[index],
-                            to: place,
-                            t: 0.0,
-                        }),
-                        delete_after_animation: false,
-                    });
-                    pieces_for_sale[index] = None;
-                }
+            if state.game_outcome != GameOutcome::Ongoing
+                && game_end_time.is_none()
+                && replay.is_none()
+            {
+                game_end_time = Some(Instant::now());
             }
         }
 
@@ -871,7 +1098,7 @@ fn run_game(
             let specular_color =
                 Vec3(config.light.specular_color);
 
-            let saturation: f32 = match game_outcome {
+            let saturation: f32 = match state.game_outcome {
                 GameOutcome::Ongoing => 1.0,
                 _ => 0.0,
             };
@@ -939,7 +1166,7 @@ fn run_game(
                 PieceType::Knight => &knight_mesh,
             };
 
-            for piece in &pieces {
+            for piece in state.pieces.iter().chain(&departing_pieces) {
                 let (texture_scale, texture) = match piece.color {
                     ChessColor::Black => {
                         (vec3(1.0, 1.0, 1.0), &black_marble_texture)
@@ -992,7 +1219,7 @@ fn run_game(
             }
 
             for (index, &tile) in buy_tiles.iter().enumerate() {
-                let piece_for_sale = pieces_for_sale[index];
+                let piece_for_sale = state.pieces_for_sale[index];
 
                 if let Some(piece_for_sale) = piece_for_sale {
                     let position = chessjam::grid_to_world(tile);
@@ -1011,6 +1238,25 @@ fn run_game(
                 }
             }
 
+            if let ControlState::PendingPromotion { to, .. } = control_state {
+                for &(tile, piece_type) in &ui::promotion_choices(to) {
+                    let position =
+                        chessjam::grid_to_world(tile) + vec3(0.0, 1.0, 0.0);
+                    let mesh = mesh_for_piece(piece_type);
+                    let color = Vec4(config.colors.promote);
+                    let mvp_matrix =
+                        view_projection_matrix * Mat4::translation(position.0);
+                    lit_render_buffer.push(RenderCommand {
+                        mesh,
+                        color,
+                        mvp_matrix,
+                        colormap: &plastic_marble_texture,
+                        texture_scale: vec3(2.0, 2.0, 2.0),
+                        texture_offset: vec3(0.5, 0.0, 0.5),
+                    });
+                }
+            }
+
             lit_render_buffer.push(RenderCommand {
                 mesh: &table_mesh,
                 color: vec4(1.0, 1.0, 1.0, 1.0),
@@ -1026,11 +1272,12 @@ fn run_game(
 
             let selection_tile = match control_state {
                 ControlState::SelectedPieceIndex(index) => {
-                    Some(pieces[index].position)
+                    Some(state.pieces[index].position)
                 }
                 ControlState::SelectedPurchaseIndex(index) => {
                     Some(buy_tiles[index])
                 }
+                ControlState::PendingPromotion { from, .. } => Some(from),
                 ControlState::Idle => None,
             };
 
@@ -1083,11 +1330,32 @@ fn run_game(
                 });
             }
 
+            if let ControlState::PendingPromotion { to, .. } = control_state {
+                for &(tile, _) in &ui::promotion_choices(to) {
+                    let position = chessjam::grid_to_world(tile) + height_offset;
+                    highlight_render_buffer.push(RenderCommand {
+                        mesh: &cube_mesh,
+                        color: Vec4(config.colors.place),
+                        mvp_matrix: view_projection_matrix
+                            * Mat4::translation(position.0),
+                        colormap: &white_texture,
+                        texture_scale: vec3(1.0, 1.0, 1.0),
+                        texture_offset: vec3(0.0, 0.0, 0.0),
+                    });
+                }
+            }
+
             for &place in &valid_purchase_placements {
                 let position = chessjam::grid_to_world(place) + height_offset;
+                let color = if suggested_placement == Some(place) {
+                    config.colors.hint
+                }
+                else {
+                    config.colors.place
+                };
                 highlight_render_buffer.push(RenderCommand {
                     mesh: &cube_mesh,
-                    color: Vec4(config.colors.place),
+                    color: Vec4(color),
                     mvp_matrix: view_projection_matrix
                         * Mat4::translation(position.0),
                     colormap: &white_texture,
@@ -1327,10 +1595,10 @@ fn run_game(
             price_tag_renderer.clear();
 
             for (index, &tile) in buy_tiles.iter().enumerate() {
-                let piece_for_sale = pieces_for_sale[index];
+                let piece_for_sale = state.pieces_for_sale[index];
 
                 if let Some(piece_for_sale) = piece_for_sale {
-                    let price = chess::buy_price(piece_for_sale);
+                    let price = chess::buy_price(&state.prices, piece_for_sale);
 
                     let tag = if piece_for_sale.discounted {
                         "SALE!"
@@ -1351,8 +1619,12 @@ fn run_game(
 
             if let ControlState::SelectedPieceIndex(index) = control_state {
                 if can_sell {
-                    let piece = &pieces[index];
-                    let refund = chess::sell_price(piece.piece_type, piece.moved);
+                    let piece = &state.pieces[index];
+                    let refund = chess::sell_price(
+                        &state.prices,
+                        piece.piece_type,
+                        piece.moved,
+                    );
 
                     price_tag_renderer.add_label(
                         &format!("{} Sell?", refund),
@@ -1379,7 +1651,7 @@ fn run_game(
             let world_text_projection =
                 Mat4::scale([1.0 / vx, 1.0 / vy, 1.0, 1.0]);
 
-            if game_outcome == GameOutcome::Ongoing {
+            if state.game_outcome == GameOutcome::Ongoing {
                 for &(ref label, pos, scale) in price_tag_renderer.labels() {
                     let screen_pos = view_projection_matrix * pos.extend(1.0);
                     let screen_pos = (screen_pos / screen_pos.0[3]).retract();
@@ -1456,20 +1728,32 @@ fn run_game(
 
             stopclock("world-text-pass", timer, stats_text);
 
-            let (black_turn_pos, white_turn_pos) = match game_outcome {
-                GameOutcome::Ongoing => match whos_turn {
+            let outcome = state.game_outcome;
+            let winner = outcome.winner();
+            let (black_turn_pos, white_turn_pos) = match (outcome, winner) {
+                (GameOutcome::Ongoing, _) => match state.whos_turn {
                     ChessColor::Black => (vec3(0.0, 4.6, 0.0), vec3(0.0, 7.0, 0.0)),
                     ChessColor::White => (vec3(0.0, 7.0, 0.0), vec3(0.0, 4.6, 0.0)),
                 },
-                GameOutcome::Stalemate => {
-                    (vec3(-1.0, 3.0, 0.0), vec3(1.0, 3.0, 0.0))
+                (_, Some(ChessColor::Black)) => {
+                    (vec3(0.0, 3.0, 0.0), vec3(0.0, 7.0, 0.0))
                 }
-                GameOutcome::Victory(winner) => match winner {
-                    ChessColor::Black => (vec3(0.0, 3.0, 0.0), vec3(0.0, 7.0, 0.0)),
-                    ChessColor::White => (vec3(0.0, 7.0, 0.0), vec3(0.0, 3.0, 0.0)),
-                },
+                (_, Some(ChessColor::White)) => {
+                    (vec3(0.0, 7.0, 0.0), vec3(0.0, 3.0, 0.0))
+                }
+                (_, None) => (vec3(-1.0, 3.0, 0.0), vec3(1.0, 3.0, 0.0)),
             };
 
+            // Bob the indicator up and down while the AI thinks
+            let bob = 0.15 * (elapsed * 6.0).sin();
+            let (black_bob, white_bob) = match (ai.is_thinking(), state.whos_turn) {
+                (true, ChessColor::Black) => (bob, 0.0),
+                (true, ChessColor::White) => (0.0, bob),
+                (false, _) => (0.0, 0.0),
+            };
+            let black_turn_pos = black_turn_pos + vec3(0.0, black_bob, 0.0);
+            let white_turn_pos = white_turn_pos + vec3(0.0, white_bob, 0.0);
+
             let ui_render_commands = {
                 let mut game_ui = vec![
                     UiRenderCommand {
@@ -1513,7 +1797,7 @@ fn run_game(
                     },
                 ];
 
-                if game_outcome == GameOutcome::Ongoing {
+                if state.game_outcome == GameOutcome::Ongoing {
                     ui_render_commands.append(&mut game_ui);
                 }
 
@@ -1540,7 +1824,7 @@ fn run_game(
                     .unwrap();
             }
 
-            if game_outcome == GameOutcome::Ongoing {
+            if state.game_outcome == GameOutcome::Ongoing {
                 let coin_positions = &[vec3(-6.45, 3.0, 0.0), vec3(5.55, 3.0, 0.0)];
 
                 for &coin_pos in coin_positions {
@@ -1602,9 +1886,47 @@ fn run_game(
                 );
             }
 
-            if game_outcome == GameOutcome::Ongoing {
+            let mode_label = match (&replay, &net) {
+                (&Some(ref replay), _) => {
+                    format!("Replay {}/{}", replay.ply(), replay.len())
+                }
+                (&None, &Some(ref net)) if !net.is_connected() => {
+                    if net.is_host() {
+                        "Waiting for an opponent".to_owned()
+                    }
+                    else {
+                        "Disconnected".to_owned()
+                    }
+                }
+                (&None, &Some(ref net)) => match net.color() {
+                    Some(color) => format!(
+                        "Online as {:?} ({} rules, {} watching)",
+                        color,
+                        state.rules.name,
+                        net.spectator_count(),
+                    ),
+                    None => format!("Watching ({} rules)", state.rules.name),
+                },
+                (&None, &None) => format!(
+                    "{} vs {} ({}, {} rules)",
+                    player_name(state.players.white),
+                    player_name(state.players.black),
+                    state.difficulty.name,
+                    state.rules.name,
+                ),
+            };
+
+            label_renderer.add_label(
+                &mode_label,
+                vec3(-7.9, 4.1, 0.0),
+                0.3,
+                &text_system,
+                &font_texture,
+            );
+
+            if state.game_outcome == GameOutcome::Ongoing || replay.is_some() {
                 label_renderer.add_label(
-                    &white_coins.to_string(),
+                    &state.white_coins.to_string(),
                     vec3(-5.8, 2.8, 0.0),
                     0.4,
                     &text_system,
@@ -1612,7 +1934,7 @@ fn run_game(
                 );
 
                 label_renderer.add_label(
-                    &black_coins.to_string(),
+                    &state.black_coins.to_string(),
                     vec3(6.2, 2.8, 0.0),
                     0.4,
                     &text_system,
@@ -1621,6 +1943,55 @@ fn run_game(
             }
 
 
+            if let (&Some(ref clock), &None) = (&state.clock, &replay) {
+                label_renderer.add_label(
+                    &clock::format_time(clock.white),
+                    vec3(-5.8, 3.4, 0.0),
+                    0.3,
+                    &text_system,
+                    &font_texture,
+                );
+
+                label_renderer.add_label(
+                    &clock::format_time(clock.black),
+                    vec3(6.2, 3.4, 0.0),
+                    0.3,
+                    &text_system,
+                    &font_texture,
+                );
+            }
+
+            if let Some(depth) = ai.depth() {
+                let x = match state.whos_turn {
+                    ChessColor::White => -6.6,
+                    ChessColor::Black => 5.4,
+                };
+                label_renderer.add_label(
+                    &format!("Thinking ({}/{})", depth, state.difficulty.depth),
+                    vec3(x, 2.2, 0.0),
+                    0.2,
+                    &text_system,
+                    &font_texture,
+                );
+            }
+
+            for &(color, button, pos) in &game_buttons {
+                let (x, y) = pos.as_tuple();
+                let scale = if hovered_button == Some((color, button)) {
+                    0.3
+                }
+                else {
+                    0.25
+                };
+                label_renderer.add_label(
+                    button.label(),
+                    vec3(x, y, 0.0),
+                    scale,
+                    &text_system,
+                    &font_texture,
+                );
+            }
+
             #[cfg(debug_assertions)]
             {
                 if show_stats {
@@ -1637,10 +2008,34 @@ fn run_game(
                 }
             }
 
-            let status_label = match game_outcome {
-                GameOutcome::Ongoing => "".into(),
+            let status_label = match state.game_outcome {
+                GameOutcome::Ongoing => match state.offer {
+                    Some(PendingOffer { from, offer: Offer::Draw }) => {
+                        format!("{:?} offers a draw", from)
+                    }
+                    Some(PendingOffer { from, offer: Offer::TakeBack }) => {
+                        format!("{:?} asks to take back", from)
+                    }
+                    None => "".into(),
+                },
                 GameOutcome::Stalemate => "Stalemate".into(),
+                GameOutcome::Draw(DrawReason::Repetition) => {
+                    "Draw by repetition".into()
+                }
+                GameOutcome::Draw(DrawReason::FiftyMoves) => {
+                    "Draw by fifty-move rule".into()
+                }
+                GameOutcome::Draw(DrawReason::InsufficientMaterial) => {
+                    "Draw: insufficient material".into()
+                }
                 GameOutcome::Victory(x) => format!("Checkmate: {:?} wins", x),
+                GameOutcome::OutOfTime(x) => {
+                    format!("Out of time: {:?} wins", game::opponent(x))
+                }
+                GameOutcome::Resigned(x) => {
+                    format!("{:?} resigns: {:?} wins", x, game::opponent(x))
+                }
+                GameOutcome::AgreedDraw => "Draw agreed".into(),
             };
 
             label_renderer.add_label(
@@ -1684,3 +2079,126 @@ fn run_game(
         }
     }
 }
+
+
+/// Starts the animations for an action that has just been applied.
+fn animate_result(
+    state: &mut GameState,
+    departing_pieces: &mut Vec<Piece>,
+    result: ActionResult,
+    buy_tiles: &[Vec2<i32>],
+    sell_tile: Vec2<i32>,
+) {
+    for &(from, to) in &result.moved {
+        if let Some(index) = chess::piece_at(to, &state.pieces) {
+            state.pieces[index].animation = Some(Animation { from, to, t: 0.0 });
+        }
+    }
+
+    if let Some((slot, place)) = result.purchased {
+        if let Some(index) = chess::piece_at(place, &state.pieces) {
+            state.pieces[index].animation = Some(Animation {
+                from: buy_tiles[slot],
+                to: place,
+                t: 0.0,
+            });
+        }
+    }
+
+    for mut piece in result.removed {
+        piece.animation = Some(Animation {
+            from: piece.position,
+            to: sell_tile,
+            t: 0.0,
+        });
+        piece.position = sell_tile;
+        piece.delete_after_animation = true;
+        departing_pieces.push(piece);
+    }
+}
+
+/// Plays an action's animations backwards, for a state that has just been
+/// rewound from `undone`.
+fn animate_undo(
+    state: &mut GameState,
+    undone: &GameState,
+    departing_pieces: &mut Vec<Piece>,
+    result: ActionResult,
+    buy_tiles: &[Vec2<i32>],
+    sell_tile: Vec2<i32>,
+) {
+    for &(from, to) in &result.moved {
+        if let Some(index) = chess::piece_at(from, &state.pieces) {
+            state.pieces[index].animation = Some(Animation {
+                from: to,
+                to: from,
+                t: 0.0,
+            });
+        }
+    }
+
+    if let Some((slot, place)) = result.purchased {
+        if let Some(index) = chess::piece_at(place, &undone.pieces) {
+            let mut piece = undone.pieces[index].clone();
+            piece.animation = Some(Animation {
+                from: place,
+                to: buy_tiles[slot],
+                t: 0.0,
+            });
+            piece.position = buy_tiles[slot];
+            piece.delete_after_animation = true;
+            departing_pieces.push(piece);
+        }
+    }
+
+    for piece in result.removed {
+        if let Some(index) = chess::piece_at(piece.position, &state.pieces) {
+            state.pieces[index].animation = Some(Animation {
+                from: sell_tile,
+                to: piece.position,
+                t: 0.0,
+            });
+        }
+    }
+}
+
+
+/// The side the camera should look from. With one human it's always theirs,
+/// and online it's always the side played on this machine.
+fn viewing_side(
+    state: &GameState,
+    net: &Option<NetGame>,
+    follow_turn: bool,
+) -> ChessColor {
+    if let Some(color) = net.as_ref().and_then(NetGame::color) {
+        return color;
+    }
+
+    match (state.players.white, state.players.black) {
+        (PlayerKind::Human, PlayerKind::Ai) => ChessColor::White,
+        (PlayerKind::Ai, PlayerKind::Human) => ChessColor::Black,
+        _ if follow_turn => state.whos_turn,
+        _ => ChessColor::White,
+    }
+}
+
+/// Cycles through human vs AI, AI vs human, human vs human and AI vs AI.
+fn next_players(players: Players) -> Players {
+    use chessjam::data::PlayerKind::*;
+
+    let (white, black) = match (players.white, players.black) {
+        (Human, Ai) => (Ai, Human),
+        (Ai, Human) => (Human, Human),
+        (Human, Human) => (Ai, Ai),
+        (Ai, Ai) => (Human, Ai),
+    };
+
+    Players { white, black }
+}
+
+fn player_name(kind: PlayerKind) -> &'static str {
+    match kind {
+        PlayerKind::Human => "Human",
+        PlayerKind::Ai => "AI",
+    }
+}
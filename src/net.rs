@@ -0,0 +1,973 @@
+//! Playing over the network.
+//!
+//! One game hosts and is the authority on everything: the other connects,
+//! gets a snapshot of the game, and from then on sends the actions its
+//! player wants to take. The host checks each one with the same
+//! `GameState::apply` it uses for its own player, and sends every action that
+//! is actually played back out along with the wallets and shop that followed,
+//! so the shop's luck only ever comes from the host. Anybody else can connect
+//! to watch, and gets the same snapshot and actions without taking part.
+//!
+//! The protocol is one message per line, except that a snapshot is followed
+//! by the save file it announces:
+//!
+//! - `hello <version>` - from a client that wants to play, as soon as it
+//!   connects.
+//! - `watch <version>` - from a client that only wants to watch.
+//! - `welcome <version> <white|black|spectator>` - the side the client plays,
+//!   if any.
+//! - `snapshot <length>` - the game so far, as `length` bytes of save file.
+//! - `submit <action>` - a client would like to take an action.
+//! - `played <white coins> <black coins> <shop> <action>` - an action was
+//!   played. The shop is written as in PGN, one word per slot.
+//! - `clock <white seconds> <black seconds>` - the time left on each side's
+//!   clock, sent just before each `played` in a timed game.
+//! - `flag <white|black>` - that side has run out of time.
+//! - `reject <reason>` - a submitted action wasn't allowed, or came from a
+//!   spectator.
+//! - `error <reason>` - sent just before the host hangs up.
+//!
+//! Actions are written as by `chess::format_action`.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use chess;
+use config::Config;
+use data::*;
+use game::{self, GameState};
+use pgn;
+use save;
+
+
+pub const PROTOCOL_VERSION: u32 = 4;
+pub const DEFAULT_PORT: u16 = 7357;
+
+/// How many seconds a handshake may take before the other end is given up on.
+const HANDSHAKE_TIMEOUT: u64 = 5;
+
+/// Saves are a few kilobytes, so anything much bigger is a mistake.
+const MAX_SNAPSHOT_LENGTH: usize = 1 << 20;
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Message {
+    Hello {
+        version: u32,
+    },
+    Watch {
+        version: u32,
+    },
+    Welcome {
+        version: u32,
+        color: Option<ChessColor>,
+    },
+    Snapshot(String),
+    Submit(Action),
+    Played {
+        action: Action,
+        white_coins: u32,
+        black_coins: u32,
+        shop: Vec<Option<PieceForSale>>,
+    },
+    Clock {
+        white: f32,
+        black: f32,
+    },
+    Flag(ChessColor),
+    Reject(String),
+    Error(String),
+}
+
+
+/// Either end of a networked game, as the game loop sees it.
+pub enum NetGame {
+    Host(Host),
+    Client(Client),
+}
+
+impl NetGame {
+    /// The side played on this machine, or `None` when only watching.
+    pub fn color(&self) -> Option<ChessColor> {
+        match *self {
+            NetGame::Host(ref host) => Some(host.color),
+            NetGame::Client(ref client) => client.color,
+        }
+    }
+
+    pub fn is_spectator(&self) -> bool {
+        self.color().is_none()
+    }
+
+    pub fn is_host(&self) -> bool {
+        match *self {
+            NetGame::Host(_) => true,
+            NetGame::Client(_) => false,
+        }
+    }
+
+    /// Whether there is somebody on the other end.
+    pub fn is_connected(&self) -> bool {
+        match *self {
+            NetGame::Host(ref host) => host.has_opponent(),
+            NetGame::Client(ref client) => client.is_connected(),
+        }
+    }
+
+    /// How many are watching, as far as this end knows.
+    pub fn spectator_count(&self) -> usize {
+        match *self {
+            NetGame::Host(ref host) => host.spectator_count(),
+            NetGame::Client(_) => 0,
+        }
+    }
+
+    /// Whether the player on this machine may take an action. A client waits
+    /// to hear back about each one before it tries another.
+    pub fn can_act(&self, state: &GameState) -> bool {
+        let waiting = match *self {
+            NetGame::Host(_) => false,
+            NetGame::Client(ref client) => client.awaiting_reply,
+        };
+
+        self.is_connected() && !waiting && self.color() == Some(state.whos_turn)
+    }
+
+    /// Takes care of connections coming and going. Call this every frame.
+    pub fn update(&mut self, state: &GameState) {
+        if let NetGame::Host(ref mut host) = *self {
+            host.update(state);
+        }
+    }
+
+    /// The next action from the other side of the network for the game to
+    /// apply, if one has arrived.
+    pub fn poll(&mut self, state: &GameState) -> Option<Action> {
+        match *self {
+            NetGame::Host(ref mut host) => host.poll(state),
+            NetGame::Client(ref mut client) => client.poll(),
+        }
+    }
+
+    /// Passes on an action taken on this machine. A host plays its own
+    /// actions straight away, but a client has to wait for the host to
+    /// send them back.
+    pub fn submit(&mut self, action: Action) {
+        if let NetGame::Client(ref mut client) = *self {
+            client.submit(action);
+        }
+    }
+
+    /// Call once an action has been applied, so the host can tell the client
+    /// and the client can catch up with the host's wallets, shop and clocks.
+    pub fn played(&mut self, state: &mut GameState, action: Action) {
+        match *self {
+            NetGame::Host(ref mut host) => host.played(state, action),
+            NetGame::Client(ref mut client) => client.played(state),
+        }
+    }
+
+    /// Tells everyone that `color` has run out of time. Only the host's
+    /// clock counts, so clients leave this to it.
+    pub fn flag(&mut self, color: ChessColor) {
+        if let NetGame::Host(ref mut host) = *self {
+            host.broadcast(&Message::Flag(color));
+        }
+    }
+
+    /// The side the host has said ran out of time, if it has.
+    pub fn take_flag(&mut self) -> Option<ChessColor> {
+        match *self {
+            NetGame::Host(_) => None,
+            NetGame::Client(ref mut client) => client.flagged.take(),
+        }
+    }
+}
+
+
+/// Hosts a game for one other player to join.
+pub struct Host {
+    color: ChessColor,
+    listener: TcpListener,
+    joins: Receiver<(Connection, bool)>,
+    join_sender: Sender<(Connection, bool)>,
+    peer: Option<Peer>,
+    spectators: Vec<Peer>,
+}
+
+impl Host {
+    pub fn bind<A: ToSocketAddrs>(
+        address: A,
+        color: ChessColor,
+    ) -> Result<Self, String> {
+        let listener = TcpListener::bind(address).map_err(|err| err.to_string())?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|err| err.to_string())?;
+
+        let (join_sender, joins) = mpsc::channel();
+
+        Ok(Host {
+            color,
+            listener,
+            joins,
+            join_sender,
+            peer: None,
+            spectators: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, String> {
+        self.listener.local_addr().map_err(|err| err.to_string())
+    }
+
+    pub fn has_opponent(&self) -> bool {
+        self.peer.is_some()
+    }
+
+    pub fn spectator_count(&self) -> usize {
+        self.spectators.len()
+    }
+
+    pub fn update(&mut self, state: &GameState) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let joins = self.join_sender.clone();
+                    thread::spawn(move || {
+                        match greet(stream) {
+                            Ok(join) => {
+                                let _ = joins.send(join);
+                            }
+                            Err(err) => eprintln!("Player couldn't join: {}", err),
+                        }
+                    });
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("Failed to accept a connection: {}", err);
+                    break;
+                }
+            }
+        }
+
+        while let Ok((mut connection, watching)) = self.joins.try_recv() {
+            if watching {
+                match welcome(&mut connection, None, state) {
+                    Ok(()) => self.spectators.push(Peer::new(connection)),
+                    Err(err) => eprintln!("Spectator couldn't join: {}", err),
+                }
+                continue;
+            }
+
+            if self.peer.is_some() {
+                let full = Message::Error("The game is full".to_owned());
+                let _ = connection.send(&full);
+                continue;
+            }
+
+            let color = Some(game::opponent(self.color));
+            match welcome(&mut connection, color, state) {
+                Ok(()) => self.peer = Some(Peer::new(connection)),
+                Err(err) => eprintln!("Player couldn't join: {}", err),
+            }
+        }
+
+        // Spectators have nothing to say, so this mostly notices them leaving
+        let spectators = mem::replace(&mut self.spectators, Vec::new());
+        self.spectators = spectators
+            .into_iter()
+            .filter_map(|mut spectator| loop {
+                match spectator.receive() {
+                    Ok(Some(Message::Submit(_))) => {
+                        let reason = "Spectators can't play".to_owned();
+                        if spectator.send(&Message::Reject(reason)).is_err() {
+                            break None;
+                        }
+                    }
+                    Ok(Some(_)) => (),
+                    Ok(None) => break Some(spectator),
+                    Err(_) => break None,
+                }
+            })
+            .collect();
+    }
+
+    /// The opponent's next action, once it has been checked. Anything they
+    /// aren't allowed to do is turned away.
+    pub fn poll(&mut self, state: &GameState) -> Option<Action> {
+        let remote_color = game::opponent(self.color);
+
+        loop {
+            let message = match self.peer {
+                Some(ref mut peer) => peer.receive(),
+                None => return None,
+            };
+
+            let action = match message {
+                Ok(Some(Message::Submit(action))) => action,
+                Ok(Some(_)) => continue,
+                Ok(None) => return None,
+                Err(err) => {
+                    eprintln!("Opponent disconnected: {}", err);
+                    self.peer = None;
+                    return None;
+                }
+            };
+
+            match check_action(state, remote_color, action) {
+                Ok(()) => return Some(action),
+                Err(reason) => {
+                    if let Some(ref mut peer) = self.peer {
+                        let _ = peer.send(&Message::Reject(reason));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn played(&mut self, state: &GameState, action: Action) {
+        if let Some(ref clock) = state.clock {
+            self.broadcast(&Message::Clock {
+                white: clock.white,
+                black: clock.black,
+            });
+        }
+
+        self.broadcast(&played_message(state, action));
+    }
+
+    /// Sends `message` to the opponent and everybody watching.
+    fn broadcast(&mut self, message: &Message) {
+        let failed = match self.peer {
+            Some(ref mut peer) => peer.send(message).is_err(),
+            None => false,
+        };
+
+        if failed {
+            eprintln!("Opponent disconnected");
+            self.peer = None;
+        }
+
+        let spectators = mem::replace(&mut self.spectators, Vec::new());
+        self.spectators = spectators
+            .into_iter()
+            .filter_map(|mut spectator| match spectator.send(message) {
+                Ok(()) => Some(spectator),
+                Err(_) => None,
+            })
+            .collect();
+    }
+}
+
+
+/// A game joined on somebody else's machine, to play or just to watch.
+pub struct Client {
+    color: Option<ChessColor>,
+    peer: Peer,
+    connected: bool,
+    awaiting_reply: bool,
+    sync: Option<(u32, u32, Vec<Option<PieceForSale>>)>,
+    clock_sync: Option<(f32, f32)>,
+    flagged: Option<ChessColor>,
+}
+
+impl Client {
+    /// Joins the game at `address`, returning it as it stands.
+    pub fn connect<A: ToSocketAddrs>(
+        address: A,
+        config: &Config,
+    ) -> Result<(Self, GameState), String> {
+        let hello = Message::Hello {
+            version: PROTOCOL_VERSION,
+        };
+        Client::join(address, config, &hello)
+    }
+
+    /// Starts watching the game at `address`, returning it as it stands.
+    pub fn watch<A: ToSocketAddrs>(
+        address: A,
+        config: &Config,
+    ) -> Result<(Self, GameState), String> {
+        let watch = Message::Watch {
+            version: PROTOCOL_VERSION,
+        };
+        Client::join(address, config, &watch)
+    }
+
+    fn join<A: ToSocketAddrs>(
+        address: A,
+        config: &Config,
+        hello: &Message,
+    ) -> Result<(Self, GameState), String> {
+        let stream = TcpStream::connect(address).map_err(|err| err.to_string())?;
+        let mut connection = Connection::new(stream)?;
+
+        connection.send(hello)?;
+
+        let color = match connection.receive()? {
+            Message::Welcome { version, color } if version == PROTOCOL_VERSION => {
+                color
+            }
+            Message::Welcome { version, .. } => {
+                return Err(format!("Host speaks protocol version {}", version))
+            }
+            Message::Error(reason) => return Err(reason),
+            other => return Err(format!("Expected welcome, got {:?}", other)),
+        };
+
+        let state = match connection.receive()? {
+            Message::Snapshot(text) => save::load_from_str(&text, config)?,
+            other => return Err(format!("Expected snapshot, got {:?}", other)),
+        };
+        connection.clear_timeout()?;
+
+        let client = Client {
+            color,
+            peer: Peer::new(connection),
+            connected: true,
+            awaiting_reply: false,
+            sync: None,
+            clock_sync: None,
+            flagged: None,
+        };
+
+        Ok((client, state))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn poll(&mut self) -> Option<Action> {
+        while self.connected {
+            match self.peer.receive() {
+                Ok(Some(Message::Played {
+                    action,
+                    white_coins,
+                    black_coins,
+                    shop,
+                })) => {
+                    self.sync = Some((white_coins, black_coins, shop));
+                    self.awaiting_reply = false;
+                    return Some(action);
+                }
+                Ok(Some(Message::Clock { white, black })) => {
+                    self.clock_sync = Some((white, black));
+                }
+                Ok(Some(Message::Flag(color))) => self.flagged = Some(color),
+                Ok(Some(Message::Reject(reason))) => {
+                    eprintln!("Host refused action: {}", reason);
+                    self.awaiting_reply = false;
+                }
+                Ok(Some(Message::Error(reason))) => {
+                    eprintln!("Host error: {}", reason);
+                }
+                Ok(Some(_)) => (),
+                Ok(None) => break,
+                Err(err) => {
+                    eprintln!("Lost connection to host: {}", err);
+                    self.connected = false;
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn submit(&mut self, action: Action) {
+        if !self.connected {
+            return;
+        }
+
+        if self.peer.send(&Message::Submit(action)).is_ok() {
+            self.awaiting_reply = true;
+        }
+        else {
+            eprintln!("Lost connection to host");
+            self.connected = false;
+        }
+    }
+
+    /// Brings the wallets, shop and clocks into line with the host's after
+    /// the last action from `poll` has been applied.
+    pub fn played(&mut self, state: &mut GameState) {
+        if let Some((white_coins, black_coins, shop)) = self.sync.take() {
+            state.white_coins = white_coins;
+            state.black_coins = black_coins;
+            state.set_shop(&shop);
+        }
+
+        if let Some((white, black)) = self.clock_sync.take() {
+            if let Some(ref mut clock) = state.clock {
+                clock.white = white;
+                clock.black = black;
+            }
+        }
+    }
+}
+
+
+/// Checks that `color` may take `action` right now. Resigning and offers
+/// don't have to wait for a side's turn.
+fn check_action(
+    state: &GameState,
+    color: ChessColor,
+    action: Action,
+) -> Result<(), String> {
+    if state.game_outcome != GameOutcome::Ongoing {
+        return Err("The game is over".to_owned());
+    }
+
+    if state.actor(action) != color {
+        return Err("It isn't your turn".to_owned());
+    }
+
+    if state.clone().apply(action).is_empty() {
+        return Err(format!("Illegal action {}", chess::format_action(action)));
+    }
+
+    Ok(())
+}
+
+fn played_message(state: &GameState, action: Action) -> Message {
+    Message::Played {
+        action,
+        white_coins: state.white_coins,
+        black_coins: state.black_coins,
+        shop: state.pieces_for_sale.to_vec(),
+    }
+}
+
+/// Waits for a newcomer's hello, turning them away if they speak a different
+/// version of the protocol. Also says whether they only want to watch.
+fn greet(stream: TcpStream) -> Result<(Connection, bool), String> {
+    let mut connection = Connection::new(stream)?;
+
+    let (version, watching) = match connection.receive()? {
+        Message::Hello { version } => (version, false),
+        Message::Watch { version } => (version, true),
+        other => return Err(format!("Expected hello, got {:?}", other)),
+    };
+
+    match version {
+        PROTOCOL_VERSION => Ok((connection, watching)),
+        version => {
+            let reason = format!(
+                "Host speaks protocol version {}, not {}",
+                PROTOCOL_VERSION, version
+            );
+            connection.send(&Message::Error(reason.clone()))?;
+            Err(reason)
+        }
+    }
+}
+
+fn welcome(
+    connection: &mut Connection,
+    color: Option<ChessColor>,
+    state: &GameState,
+) -> Result<(), String> {
+    connection.send(&Message::Welcome {
+        version: PROTOCOL_VERSION,
+        color,
+    })?;
+    connection.send(&Message::Snapshot(save::save_to_string(state)?))?;
+    connection.clear_timeout()
+}
+
+
+/// A blocking connection, used as it is for handshakes.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Result<Self, String> {
+        let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT);
+        let setup = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_nodelay(true))
+            .and_then(|_| stream.set_read_timeout(Some(timeout)))
+            .and_then(|_| stream.try_clone());
+
+        match setup {
+            Ok(writer) => Ok(Connection {
+                reader: BufReader::new(stream),
+                writer,
+            }),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn clear_timeout(&mut self) -> Result<(), String> {
+        self.writer
+            .set_read_timeout(None)
+            .map_err(|err| err.to_string())
+    }
+
+    fn send(&mut self, message: &Message) -> Result<(), String> {
+        write_message(&mut self.writer, message).map_err(|err| err.to_string())
+    }
+
+    fn receive(&mut self) -> Result<Message, String> {
+        read_message(&mut self.reader)
+    }
+}
+
+
+/// A connection after the handshake, read on its own thread so the game loop
+/// never waits on it.
+struct Peer {
+    writer: TcpStream,
+    incoming: Receiver<Result<Message, String>>,
+}
+
+impl Peer {
+    fn new(connection: Connection) -> Self {
+        let Connection { mut reader, writer } = connection;
+        let (sender, incoming) = mpsc::channel();
+
+        thread::spawn(move || loop {
+            let message = read_message(&mut reader);
+            let failed = message.is_err();
+
+            if sender.send(message).is_err() || failed {
+                break;
+            }
+        });
+
+        Peer { writer, incoming }
+    }
+
+    fn send(&mut self, message: &Message) -> Result<(), String> {
+        write_message(&mut self.writer, message).map_err(|err| err.to_string())
+    }
+
+    /// The next message if there is one yet, or an error once the connection
+    /// has gone.
+    fn receive(&mut self) -> Result<Option<Message>, String> {
+        match self.incoming.try_recv() {
+            Ok(Ok(message)) => Ok(Some(message)),
+            Ok(Err(err)) => Err(err),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err("Connection closed".to_owned()),
+        }
+    }
+}
+
+
+fn write_message<W: Write>(out: &mut W, message: &Message) -> io::Result<()> {
+    match *message {
+        Message::Hello { version } => writeln!(out, "hello {}", version)?,
+        Message::Watch { version } => writeln!(out, "watch {}", version)?,
+        Message::Welcome { version, color } => {
+            let role = color.map_or("spectator", chess::color_name);
+            writeln!(out, "welcome {} {}", version, role)?
+        }
+        Message::Snapshot(ref text) => {
+            writeln!(out, "snapshot {}", text.len())?;
+            out.write_all(text.as_bytes())?;
+        }
+        Message::Submit(action) => {
+            writeln!(out, "submit {}", chess::format_action(action))?
+        }
+        Message::Played {
+            action,
+            white_coins,
+            black_coins,
+            ref shop,
+        } => writeln!(
+            out,
+            "played {} {} {} {}",
+            white_coins,
+            black_coins,
+            pgn::format_shop(shop),
+            chess::format_action(action)
+        )?,
+        Message::Clock { white, black } => {
+            writeln!(out, "clock {:.3} {:.3}", white, black)?
+        }
+        Message::Flag(color) => {
+            writeln!(out, "flag {}", chess::color_name(color))?
+        }
+        Message::Reject(ref reason) => writeln!(out, "reject {}", reason)?,
+        Message::Error(ref reason) => writeln!(out, "error {}", reason)?,
+    }
+
+    out.flush()
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> Result<Message, String> {
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(0) => return Err("Connection closed".to_owned()),
+        Ok(_) => (),
+        Err(err) => return Err(err.to_string()),
+    }
+
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let bad = || format!("Bad message '{}'", line.trim());
+    let number = |index: usize| {
+        words
+            .get(index)
+            .and_then(|word| word.parse::<u32>().ok())
+            .ok_or_else(&bad)
+    };
+    let seconds = |index: usize| {
+        words
+            .get(index)
+            .and_then(|word| word.parse::<f32>().ok())
+            .ok_or_else(&bad)
+    };
+    let rest = |index: usize| words[index.min(words.len())..].join(" ");
+
+    match words.first() {
+        Some(&"hello") => Ok(Message::Hello {
+            version: number(1)?,
+        }),
+        Some(&"watch") => Ok(Message::Watch {
+            version: number(1)?,
+        }),
+        Some(&"welcome") => Ok(Message::Welcome {
+            version: number(1)?,
+            color: match words.get(2) {
+                Some(&"spectator") => None,
+                Some(&word) => {
+                    Some(chess::parse_color(word).ok_or_else(&bad)?)
+                }
+                None => return Err(bad()),
+            },
+        }),
+        Some(&"snapshot") => {
+            let length = number(1)? as usize;
+            if length > MAX_SNAPSHOT_LENGTH {
+                return Err(format!("Snapshot is too big ({} bytes)", length));
+            }
+
+            let mut buffer = vec![0; length];
+            input
+                .read_exact(&mut buffer)
+                .map_err(|err| err.to_string())?;
+            String::from_utf8(buffer)
+                .map(Message::Snapshot)
+                .map_err(|err| err.to_string())
+        }
+        Some(&"submit") => chess::parse_action(&rest(1))
+            .map(Message::Submit)
+            .ok_or_else(&bad),
+        Some(&"played") => {
+            let shop_end = 3 + game::SHOP_SIZE;
+            let shop = if words.len() > shop_end {
+                pgn::parse_shop(&words[3..shop_end].join(" "))
+            }
+            else {
+                None
+            };
+
+            Ok(Message::Played {
+                white_coins: number(1)?,
+                black_coins: number(2)?,
+                shop: shop.ok_or_else(&bad)?,
+                action: chess::parse_action(&rest(shop_end)).ok_or_else(&bad)?,
+            })
+        }
+        Some(&"clock") => Ok(Message::Clock {
+            white: seconds(1)?,
+            black: seconds(2)?,
+        }),
+        Some(&"flag") => words
+            .get(1)
+            .and_then(|&word| chess::parse_color(word))
+            .map(Message::Flag)
+            .ok_or_else(&bad),
+        Some(&"reject") => Ok(Message::Reject(rest(1))),
+        Some(&"error") => Ok(Message::Error(rest(1))),
+        _ => Err(bad()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use config::CONFIG;
+
+    /// Keeps calling `poll` until it comes up with something, for up to five
+    /// seconds.
+    fn wait_for<T, F: FnMut() -> Option<T>>(mut poll: F) -> T {
+        for _ in 0..500 {
+            if let Some(value) = poll() {
+                return value;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("Timed out");
+    }
+
+    fn action(text: &str) -> Action {
+        chess::parse_action(text).unwrap()
+    }
+
+    /// Has a client join `host` with `hello`, keeping the host going until the
+    /// client has either joined or given up.
+    fn join(
+        host: &mut Host,
+        state: &GameState,
+        hello: Message,
+    ) -> Result<(Client, GameState), String> {
+        let address = host.local_addr().unwrap();
+        let (sender, joined) = mpsc::channel();
+
+        thread::spawn(move || {
+            let _ = sender.send(Client::join(address, &CONFIG, &hello));
+        });
+
+        wait_for(|| {
+            host.update(state);
+            joined.try_recv().ok()
+        })
+    }
+
+    #[test]
+    fn client_plays_through_the_host() {
+        let mut state = GameState::new_seeded(&CONFIG, 7);
+        let mut host = Host::bind("127.0.0.1:0", ChessColor::White).unwrap();
+
+        let hello = Message::Hello {
+            version: PROTOCOL_VERSION,
+        };
+        let (mut client, mut client_state) =
+            join(&mut host, &state, hello).unwrap();
+
+        assert!(host.has_opponent());
+        assert_eq!(client.color, Some(ChessColor::Black));
+        assert_eq!(client_state.fen(), state.fen());
+        assert_eq!(client_state.pieces_for_sale, state.pieces_for_sale);
+        assert_eq!(client_state.rng.seed(), state.rng.seed());
+
+        // Out of turn, so the host turns it away
+        client.submit(action("e7e5"));
+        wait_for(|| {
+            assert_eq!(host.poll(&state), None);
+            assert_eq!(client.poll(), None);
+            if client.awaiting_reply {
+                None
+            }
+            else {
+                Some(())
+            }
+        });
+
+        let e4 = action("e2e4");
+        assert!(!state.apply(e4).is_empty());
+        host.played(&state, e4);
+
+        let received = wait_for(|| client.poll());
+        assert_eq!(received, e4);
+        client_state.apply(received);
+        client.played(&mut client_state);
+
+        let e5 = action("e7e5");
+        client.submit(e5);
+        assert_eq!(wait_for(|| host.poll(&state)), e5);
+        assert!(!state.apply(e5).is_empty());
+
+        // Whatever the host ends up with is what the client gets
+        state.white_coins = 7;
+        state.black_coins = 2;
+        state.pieces_for_sale = [
+            Some(PieceForSale {
+                piece_type: PieceType::Queen,
+                discounted: true,
+            }),
+            None,
+            Some(PieceForSale {
+                piece_type: PieceType::Knight,
+                discounted: false,
+            }),
+        ];
+        host.played(&state, e5);
+
+        let received = wait_for(|| client.poll());
+        assert_eq!(received, e5);
+        client_state.apply(received);
+        client.played(&mut client_state);
+
+        assert_eq!(client_state.white_coins, 7);
+        assert_eq!(client_state.black_coins, 2);
+        assert_eq!(client_state.pieces_for_sale, state.pieces_for_sale);
+        assert_eq!(client_state.fen(), state.fen());
+    }
+
+    #[test]
+    fn spectators_follow_along_but_cant_play() {
+        let mut state = GameState::new_seeded(&CONFIG, 7);
+        let mut host = Host::bind("127.0.0.1:0", ChessColor::White).unwrap();
+
+        let watch = Message::Watch {
+            version: PROTOCOL_VERSION,
+        };
+        let (mut spectator, mut watched) =
+            join(&mut host, &state, watch).unwrap();
+
+        assert!(!host.has_opponent());
+        assert_eq!(host.spectator_count(), 1);
+        assert_eq!(spectator.color, None);
+        assert_eq!(watched.fen(), state.fen());
+        assert_eq!(watched.pieces_for_sale, state.pieces_for_sale);
+
+        spectator.submit(action("e2e4"));
+        assert!(spectator.awaiting_reply);
+        wait_for(|| {
+            host.update(&state);
+            assert_eq!(host.poll(&state), None);
+            assert_eq!(spectator.poll(), None);
+            if spectator.awaiting_reply {
+                None
+            }
+            else {
+                Some(())
+            }
+        });
+        assert!(state.history.is_empty());
+
+        let e4 = action("e2e4");
+        assert!(!state.apply(e4).is_empty());
+        host.played(&state, e4);
+
+        let received = wait_for(|| spectator.poll());
+        assert_eq!(received, e4);
+        watched.apply(received);
+        spectator.played(&mut watched);
+
+        assert_eq!(watched.fen(), state.fen());
+        assert_eq!(watched.pieces_for_sale, state.pieces_for_sale);
+        assert_eq!(host.spectator_count(), 1);
+    }
+
+    #[test]
+    fn other_protocol_versions_are_turned_away() {
+        let state = GameState::new_seeded(&CONFIG, 7);
+        let mut host = Host::bind("127.0.0.1:0", ChessColor::White).unwrap();
+
+        let hello = Message::Hello {
+            version: PROTOCOL_VERSION + 1,
+        };
+        let err = join(&mut host, &state, hello).err().unwrap();
+
+        assert!(err.contains("protocol version"), "{}", err);
+        host.update(&state);
+        assert!(!host.has_opponent());
+    }
+}
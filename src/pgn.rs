@@ -0,0 +1,689 @@
+//! Writing games out as PGN, and reading them back in.
+//!
+//! Chess moves are plain SAN so any PGN reader can follow the board, as long
+//! as it honours the `FEN` tag for the starting position. Everything to do
+//! with the shop is carried in comments using a `[%purchess ...]` command,
+//! which readers that don't understand it will show or ignore:
+//!
+//! - `[%purchess sell <piece> <square> <coins>]` - the side to move sold the
+//!   piece on `square` for `coins`.
+//! - `[%purchess buy <slot> <piece> <square> <coins>]` - the side to move
+//!   bought the piece in shop `slot` (counting from zero) and placed it on
+//!   `square`, paying `coins`.
+//! - `[%purchess shop <slots>]` - the shop after the action before it, when
+//!   that action restocked it or bought from it. Each slot is a piece
+//!   letter, followed by `*` if it is discounted, or `-` if it is empty.
+//! - `[%purchess place <from> <to>]` - the side to move put its king on
+//!   `to`, under rules that allow that before a side's first move.
+//! - `[%purchess resign <white|black>]`, `[%purchess draw]` and
+//!   `[%purchess flag <white|black>]` - the game ended with that side
+//!   resigning, a draw being agreed, or that side running out of time. These
+//!   come last, since the board can't show them.
+//!
+//! Shop actions come before the move of the player who made them. The shop
+//! the game started with is in the `Shop` tag, using the same slot notation,
+//! and the `Seed` tag holds the seed it was played with, so importing it
+//! carries on with the same luck. `Rules` names the rule preset.
+//!
+//! Ordinary PGN can be imported too. Without a `FEN` tag the game starts from
+//! the standard chess position, and without `Shop` the shop is stocked at
+//! random. If the moves leave the game going but the `Result` tag says it's
+//! over, the loser is taken to have resigned, or a draw to have been agreed.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use pleco::BitMove;
+
+use chess;
+use config::Config;
+use data::*;
+use game::{self, GameState};
+use rng::{self, GameRng};
+
+
+const LINE_WIDTH: usize = 80;
+const STANDARD_FEN: &str =
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+
+enum Token {
+    Move(String),
+    Comment(String),
+}
+
+
+pub fn export_pgn(state: &GameState) -> String {
+    let result = result_text(state.game_outcome);
+    let player = |color| {
+        if state.players.is_ai(color) {
+            "AI"
+        }
+        else {
+            "Human"
+        }
+    };
+
+    let tags = [
+        ("Event", "Purchess game".to_owned()),
+        ("Site", "?".to_owned()),
+        ("Date", "????.??.??".to_owned()),
+        ("Round", "?".to_owned()),
+        ("White", player(ChessColor::White).to_owned()),
+        ("Black", player(ChessColor::Black).to_owned()),
+        ("Result", result.to_owned()),
+        ("Variant", "Purchess".to_owned()),
+        ("SetUp", "1".to_owned()),
+        ("FEN", state.start_fen.clone()),
+        ("Shop", format_shop(&state.start_shop)),
+        ("Seed", state.rng.seed().to_string()),
+        ("Rules", state.rules.name.clone()),
+    ];
+
+    let mut pgn = String::new();
+    for &(name, ref value) in &tags {
+        pgn.push_str(&format!("[{} \"{}\"]\n", name, escape(value)));
+    }
+    pgn.push('\n');
+
+    let mut tokens = movetext(state);
+    tokens.push(result.to_owned());
+
+    let mut line = String::new();
+    for token in tokens {
+        if !line.is_empty() && line.len() + 1 + token.len() > LINE_WIDTH {
+            pgn.push_str(&line);
+            pgn.push('\n');
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&token);
+    }
+    pgn.push_str(&line);
+    pgn.push('\n');
+
+    pgn
+}
+
+pub fn save_pgn<P: AsRef<Path>>(
+    path: P,
+    state: &GameState,
+) -> Result<(), String> {
+    let text = export_pgn(state);
+
+    let mut file = File::create(path).map_err(|err| err.to_string())?;
+    file.write_all(text.as_bytes())
+        .map_err(|err| err.to_string())
+}
+
+/// Replays a PGN game, returning the state after its last move.
+pub fn import_pgn(config: &Config, text: &str) -> Result<GameState, String> {
+    let (tags, tokens) = parse_pgn(text);
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|&&(ref tag, _)| tag == name)
+            .map(|&(_, ref value)| value.as_str())
+    };
+
+    let fen = tag("FEN").unwrap_or(STANDARD_FEN);
+    let rules = match tag("Rules") {
+        Some(name) => game::rule_set(config, name)?,
+        None => game::rules(config),
+    };
+    let seed = match tag("Seed") {
+        Some(text) => rng::parse_seed(text)
+            .ok_or_else(|| format!("Invalid seed '{}'", text))?,
+        None => GameRng::random().seed(),
+    };
+    let mut state = GameState::from_fen_with_rules(config, fen, rules, seed)?;
+
+    if let Some(text) = tag("Shop") {
+        let shop = parse_shop(text)
+            .ok_or_else(|| format!("Invalid shop '{}'", text))?;
+        state.set_shop(&shop);
+        state.start_shop = state.pieces_for_sale;
+    }
+
+    let kind = |name| match tag(name) {
+        Some("AI") => PlayerKind::Ai,
+        _ => PlayerKind::Human,
+    };
+    state.players = Players {
+        white: kind("White"),
+        black: kind("Black"),
+    };
+
+    for token in tokens {
+        match token {
+            Token::Move(san) => {
+                let mov = find_san(&state, &san)
+                    .ok_or_else(|| format!("Illegal move '{}'", san))?;
+                let shop = state.pieces_for_sale;
+                state.apply_move(mov);
+                state.set_shop(&shop);
+            }
+            Token::Comment(comment) => {
+                apply_annotation(&mut state, &comment)?;
+            }
+        }
+    }
+
+    if state.game_outcome == GameOutcome::Ongoing {
+        state.game_outcome = match tag("Result") {
+            Some("1-0") => GameOutcome::Resigned(ChessColor::Black),
+            Some("0-1") => GameOutcome::Resigned(ChessColor::White),
+            Some("1/2-1/2") => GameOutcome::AgreedDraw,
+            _ => GameOutcome::Ongoing,
+        };
+    }
+
+    Ok(state)
+}
+
+pub fn load_pgn<P: AsRef<Path>>(
+    path: P,
+    config: &Config,
+) -> Result<GameState, String> {
+    let mut file = File::open(path).map_err(|err| err.to_string())?;
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer)
+        .map_err(|err| err.to_string())?;
+
+    import_pgn(config, &buffer)
+}
+
+pub fn format_shop(shop: &[Option<PieceForSale>]) -> String {
+    shop.iter()
+        .map(|slot| match *slot {
+            Some(piece) => {
+                let letter = chess::fen_char(ChessColor::White, piece.piece_type);
+                if piece.discounted {
+                    format!("{}*", letter)
+                }
+                else {
+                    letter.to_string()
+                }
+            }
+            None => "-".to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn parse_shop(text: &str) -> Option<Vec<Option<PieceForSale>>> {
+    text.split_whitespace()
+        .map(|slot| {
+            if slot == "-" {
+                return Some(None);
+            }
+
+            let discounted = slot.ends_with('*');
+            let mut chars = slot.trim_right_matches('*').chars();
+            let piece_type = match (chars.next(), chars.next()) {
+                (Some(ch), None) => chess::piece_from_fen_char(ch)?.1,
+                _ => return None,
+            };
+
+            Some(Some(PieceForSale {
+                piece_type,
+                discounted,
+            }))
+        })
+        .collect()
+}
+
+pub fn result_text(outcome: GameOutcome) -> &'static str {
+    match outcome {
+        GameOutcome::Ongoing => "*",
+        GameOutcome::Stalemate
+        | GameOutcome::Draw(_)
+        | GameOutcome::AgreedDraw => "1/2-1/2",
+        GameOutcome::Victory(ChessColor::White)
+        | GameOutcome::OutOfTime(ChessColor::Black)
+        | GameOutcome::Resigned(ChessColor::Black) => "1-0",
+        GameOutcome::Victory(ChessColor::Black)
+        | GameOutcome::OutOfTime(ChessColor::White)
+        | GameOutcome::Resigned(ChessColor::White) => "0-1",
+    }
+}
+
+fn movetext(state: &GameState) -> Vec<String> {
+    let (mut fullmove_number, first_color) =
+        match chess::parse_fen(&state.start_fen) {
+            Ok(parsed) => (parsed.move_state.fullmove_number, parsed.whos_turn),
+            Err(_) => (1, ChessColor::White),
+        };
+
+    let mut tokens = Vec::new();
+    let mut shop = state.start_shop.to_vec();
+    let mut needs_number = first_color == ChessColor::Black;
+
+    for entry in &state.history {
+        let letter = chess::fen_char(ChessColor::White, entry.piece_type);
+
+        match entry.action {
+            Action::Sell(position) => {
+                tokens.push(format!(
+                    "{{[%purchess sell {} {} {}]}}",
+                    letter,
+                    chess::square_name(position),
+                    entry.coins,
+                ));
+                needs_number = true;
+            }
+            Action::Buy { slot, place } => {
+                tokens.push(format!(
+                    "{{[%purchess buy {} {} {} {}]}}",
+                    slot,
+                    letter,
+                    chess::square_name(place),
+                    entry.coins,
+                ));
+                needs_number = true;
+
+                // Black buying under rules where that ends the turn
+                let passed = state.rules.purchases_end_turn;
+                if passed && entry.color == ChessColor::Black {
+                    fullmove_number += 1;
+                }
+            }
+            Action::Move { from, to, .. } if entry.san.is_none() => {
+                tokens.push(format!(
+                    "{{[%purchess place {} {}]}}",
+                    chess::square_name(from),
+                    chess::square_name(to),
+                ));
+                needs_number = true;
+            }
+            Action::Move { .. } => {
+                let san = entry.san.clone().unwrap_or_default();
+
+                match entry.color {
+                    ChessColor::White => {
+                        tokens.push(format!("{}. {}", fullmove_number, san));
+                        needs_number = false;
+                    }
+                    ChessColor::Black => {
+                        if needs_number {
+                            tokens.push(format!("{}... {}", fullmove_number, san));
+                        }
+                        else {
+                            tokens.push(san);
+                        }
+                        fullmove_number += 1;
+                        needs_number = false;
+                    }
+                }
+            }
+            // Resigning and offers never make it into the history
+            Action::Resign(_) | Action::Offer(..) | Action::Answer(..) => (),
+        }
+
+        if entry.shop != shop {
+            tokens.push(format!(
+                "{{[%purchess shop {}]}}",
+                format_shop(&entry.shop),
+            ));
+            needs_number = true;
+        }
+
+        shop = entry.shop.clone();
+    }
+
+    match state.game_outcome {
+        GameOutcome::Resigned(color) => tokens.push(format!(
+            "{{[%purchess resign {}]}}",
+            chess::color_name(color)
+        )),
+        GameOutcome::AgreedDraw => tokens.push("{[%purchess draw]}".to_owned()),
+        GameOutcome::OutOfTime(color) => tokens.push(format!(
+            "{{[%purchess flag {}]}}",
+            chess::color_name(color)
+        )),
+        _ => (),
+    }
+
+    tokens
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn unescape(value: &str) -> String {
+    value.replace("\\\"", "\"").replace("\\\\", "\\")
+}
+
+fn parse_pgn(text: &str) -> (Vec<(String, String)>, Vec<Token>) {
+    let mut tags = Vec::new();
+    let mut movetext = String::new();
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+
+        if movetext.is_empty() && trimmed.starts_with('[') {
+            let inner = trimmed.trim_left_matches('[').trim_right_matches(']');
+            let mut parts = inner.splitn(2, ' ');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                let value = value.trim().trim_matches('"');
+                tags.push((name.to_owned(), unescape(value)));
+            }
+        }
+        else if !trimmed.is_empty() || !movetext.is_empty() {
+            movetext.push_str(line);
+            movetext.push('\n');
+        }
+    }
+
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = movetext.chars();
+
+    while let Some(ch) = chars.next() {
+        let separator = match ch {
+            '{' => {
+                let comment = chars.by_ref().take_while(|&ch| ch != '}').collect();
+                tokens.push(Token::Comment(comment));
+                true
+            }
+            ';' => {
+                chars.by_ref().take_while(|&ch| ch != '\n').count();
+                true
+            }
+            '(' => {
+                let mut depth = 1;
+                for ch in chars.by_ref() {
+                    match ch {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => (),
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                true
+            }
+            _ if ch.is_whitespace() => true,
+            _ => {
+                word.push(ch);
+                false
+            }
+        };
+
+        if separator && !word.is_empty() {
+            push_word(&mut tokens, &word);
+            word.clear();
+        }
+    }
+
+    if !word.is_empty() {
+        push_word(&mut tokens, &word);
+    }
+
+    (tags, tokens)
+}
+
+fn push_word(tokens: &mut Vec<Token>, word: &str) {
+    let san = match word.rfind('.') {
+        Some(index) => &word[index + 1..],
+        None => word,
+    };
+    let is_number = san.chars().all(|ch| ch.is_ascii_digit());
+    let is_result = ["1-0", "0-1", "1/2-1/2", "*"].contains(&word);
+
+    if !is_number && !is_result && !san.starts_with('$') {
+        tokens.push(Token::Move(san.to_owned()));
+    }
+}
+
+fn find_san(state: &GameState, san: &str) -> Option<BitMove> {
+    let normalize = |san: &str| {
+        san.chars()
+            .filter(|&ch| !"+#!?=".contains(ch))
+            .map(|ch| if ch == '0' { 'O' } else { ch })
+            .collect::<String>()
+    };
+    let wanted = normalize(san);
+    let board = state.board.pleco();
+
+    board
+        .generate_moves()
+        .iter()
+        .find(|&&mov| {
+            normalize(&chess::move_san(board, &state.pieces, mov)) == wanted
+        })
+        .cloned()
+}
+
+fn apply_annotation(state: &mut GameState, comment: &str) -> Result<(), String> {
+    let start = match comment.find("[%purchess ") {
+        Some(start) => start,
+        None => return Ok(()),
+    };
+    let command = comment[start + 1..]
+        .split(']')
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .skip(1)
+        .collect::<Vec<_>>();
+    let invalid = || format!("Invalid annotation '{}'", comment.trim());
+
+    match command.first() {
+        Some(&"sell") if command.len() == 4 => {
+            let position = chess::parse_square(command[2]).ok_or_else(&invalid)?;
+            let result = state.sell_piece(position);
+            if result.removed.is_empty() {
+                return Err(invalid());
+            }
+        }
+        Some(&"buy") if command.len() == 5 => {
+            let slot = command[1].parse().map_err(|_| invalid())?;
+            let place = chess::parse_square(command[3]).ok_or_else(&invalid)?;
+            let shop = state.pieces_for_sale;
+            let result = state.buy_piece(slot, place);
+            if result.purchased.is_none() {
+                return Err(invalid());
+            }
+            // As with moves, any restock is in the annotation that follows
+            state.set_shop(&shop);
+        }
+        Some(&"place") if command.len() == 3 => {
+            let from = chess::parse_square(command[1]).ok_or_else(&invalid)?;
+            let to = chess::parse_square(command[2]).ok_or_else(&invalid)?;
+            if !state.is_king_placement(from, to) {
+                return Err(invalid());
+            }
+            state.move_piece(from, to, None);
+        }
+        Some(&"shop") => {
+            let shop = parse_shop(&command[1..].join(" ")).ok_or_else(&invalid)?;
+            state.set_shop(&shop);
+        }
+        Some(&"resign") if command.len() == 2 => {
+            let color = chess::parse_color(command[1]).ok_or_else(&invalid)?;
+            if state.resign(color).is_empty() {
+                return Err(invalid());
+            }
+        }
+        Some(&"draw") if command.len() == 1 => {
+            if state.game_outcome != GameOutcome::Ongoing {
+                return Err(invalid());
+            }
+            state.game_outcome = GameOutcome::AgreedDraw;
+        }
+        Some(&"flag") if command.len() == 2 => {
+            let color = chess::parse_color(command[1]).ok_or_else(&invalid)?;
+            if state.game_outcome != GameOutcome::Ongoing {
+                return Err(invalid());
+            }
+            state.flag(color);
+        }
+        _ => return Err(invalid()),
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::CONFIG;
+
+    fn round_trip(ending: fn(&mut GameState)) -> GameOutcome {
+        let mut state = GameState::new_seeded(&CONFIG, 3);
+        state.apply(chess::parse_action("e2e4").unwrap());
+        ending(&mut state);
+
+        import_pgn(&CONFIG, &export_pgn(&state)).unwrap().game_outcome
+    }
+
+    #[test]
+    fn endings_off_the_board_survive_export() {
+        assert_eq!(
+            round_trip(|state| {
+                state.resign(ChessColor::Black);
+            }),
+            GameOutcome::Resigned(ChessColor::Black)
+        );
+        assert_eq!(
+            round_trip(|state| {
+                state.apply(Action::Offer(ChessColor::White, Offer::Draw));
+                state.apply(Action::Answer(ChessColor::Black, true));
+            }),
+            GameOutcome::AgreedDraw
+        );
+        assert_eq!(
+            round_trip(|state| state.flag(ChessColor::Black)),
+            GameOutcome::OutOfTime(ChessColor::Black)
+        );
+    }
+
+    fn game(rules: &str, stock: &[PieceType]) -> GameState {
+        let rules = game::rule_set(&CONFIG, rules).unwrap();
+        let mut state = GameState::with_rules(&CONFIG, rules, 3);
+
+        for (slot, &piece_type) in stock.iter().enumerate() {
+            state.pieces_for_sale[slot] = Some(PieceForSale {
+                piece_type,
+                discounted: false,
+            });
+        }
+        state.start_shop = state.pieces_for_sale;
+        state
+    }
+
+    /// Puts `stock` up for sale, as if the last restock had come up with it.
+    fn stock(state: &mut GameState, stock: &[PieceType]) {
+        let shop = stock
+            .iter()
+            .map(|&piece_type| {
+                Some(PieceForSale {
+                    piece_type,
+                    discounted: false,
+                })
+            })
+            .collect::<Vec<_>>();
+        state.set_shop(&shop);
+    }
+
+    fn play(state: &mut GameState, actions: &[&str]) {
+        for text in actions {
+            let action = chess::parse_action(text).unwrap();
+            assert!(!state.apply(action).is_empty(), "{} was refused", text);
+        }
+    }
+
+    /// Exports `state` and checks that importing it again gets back the same
+    /// game. Returns the PGN for any further checks.
+    fn assert_round_trip(state: &GameState) -> String {
+        let pgn = export_pgn(state);
+        assert_imports_as(&pgn, state);
+        pgn
+    }
+
+    fn assert_imports_as(pgn: &str, state: &GameState) {
+        let imported = import_pgn(&CONFIG, pgn).unwrap();
+
+        assert_eq!(imported.fen(), state.fen(), "{}", pgn);
+        assert_eq!(imported.white_coins, state.white_coins, "{}", pgn);
+        assert_eq!(imported.black_coins, state.black_coins, "{}", pgn);
+        assert_eq!(imported.pieces_for_sale, state.pieces_for_sale, "{}", pgn);
+        assert_eq!(imported.history, state.history, "{}", pgn);
+    }
+
+    #[test]
+    fn sales_purchases_and_restocks_survive_export() {
+        let mut state = game(
+            "standard",
+            &[PieceType::Pawn, PieceType::Knight, PieceType::Queen],
+        );
+        play(
+            &mut state,
+            &[
+                "sell a2", "buy 0 a2", "e2e4", "sell h7", "buy 1 b8", "e7e5",
+                "d2d4",
+            ],
+        );
+
+        let pgn = assert_round_trip(&state);
+        assert!(pgn.contains("{[%purchess sell P a2 4]}"));
+        assert!(pgn.contains("{[%purchess buy 0 P a2 4]}"));
+        assert!(pgn.contains("{[%purchess buy 1 N b8 3]}"));
+        assert!(pgn.contains("[%purchess shop "));
+    }
+
+    #[test]
+    fn king_placements_survive_export() {
+        let mut state = game("kings", &[PieceType::Pawn, PieceType::Knight]);
+        play(&mut state, &["e1g1", "e2e4", "e8c8", "e7e5"]);
+
+        let pgn = assert_round_trip(&state);
+        assert!(pgn.contains("{[%purchess place e1 g1]}"));
+        assert!(pgn.contains("{[%purchess place e8 c8]}"));
+    }
+
+    #[test]
+    fn black_purchases_that_end_the_turn_count_as_a_move() {
+        let mut state =
+            game("drops", &[PieceType::Pawn, PieceType::Knight, PieceType::Rook]);
+        play(&mut state, &["e2e4"]);
+        stock(&mut state, &[PieceType::Pawn, PieceType::Knight, PieceType::Rook]);
+        play(&mut state, &["buy 1 b8", "d2d4", "e7e5"]);
+        assert_eq!(state.move_state.fullmove_number, 3);
+
+        let pgn = assert_round_trip(&state);
+        assert!(pgn.contains("2. d4"));
+        assert!(pgn.contains("2... e5"));
+    }
+
+    #[test]
+    fn the_shop_comes_from_annotations_rather_than_luck() {
+        let drops = [PieceType::Pawn, PieceType::Knight, PieceType::Rook];
+        let mut state = game("drops", &drops);
+        play(&mut state, &["buy 1 b1"]);
+        stock(&mut state, &drops);
+        play(&mut state, &["buy 1 b8", "e2e4"]);
+
+        let pgn = assert_round_trip(&state);
+        assert_eq!(pgn.matches("[%purchess shop ").count(), 2, "{}", pgn);
+
+        // A different seed restocks differently, but the annotations win
+        let reseeded = pgn.replace("[Seed \"3\"]", "[Seed \"4\"]");
+        assert_ne!(reseeded, pgn);
+        assert_imports_as(&reseeded, &state);
+    }
+
+    #[test]
+    fn decisive_results_without_mate_are_resignations() {
+        let pgn = "[Result \"0-1\"]\n\n1. e4 e5 0-1\n";
+        let state = import_pgn(&CONFIG, pgn).unwrap();
+
+        assert_eq!(state.game_outcome, GameOutcome::Resigned(ChessColor::White));
+    }
+}
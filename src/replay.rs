@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use config::Config;
+use data::*;
+use game::{ActionResult, GameState};
+use pgn;
+
+
+/// Steps back and forth through a finished (or unfinished) game.
+///
+/// Every ply is kept as a full `GameState`, so stepping never has to undo
+/// anything - it only re-applies the step's action to a scratch copy to find
+/// out what moved, for animation.
+pub struct Replay {
+    states: Vec<GameState>,
+    actions: Vec<Action>,
+    ply: usize,
+}
+
+impl Replay {
+    pub fn new(config: &Config, game: &GameState) -> Result<Self, String> {
+        let mut state = GameState::from_fen_with_rules(
+            config,
+            &game.start_fen,
+            game.rules.clone(),
+            game.rng.seed(),
+        )?;
+        state.set_shop(&game.start_shop);
+        state.start_shop = game.start_shop;
+        state.players = game.players;
+
+        let mut states = vec![state.clone()];
+        let mut actions = Vec::with_capacity(game.history.len());
+
+        for entry in &game.history {
+            let result = state.apply(entry.action);
+
+            if result.is_empty() {
+                return Err(format!("Couldn't replay {:?}", entry.action));
+            }
+
+            state.set_shop(&entry.shop);
+            states.push(state.clone());
+            actions.push(entry.action);
+        }
+
+        // Resigning, agreeing a draw and running out of time leave nothing in
+        // the history, so the ending has to be carried over
+        if let Some(last) = states.last_mut() {
+            if last.game_outcome == GameOutcome::Ongoing {
+                last.game_outcome = game.game_outcome;
+            }
+        }
+
+        Ok(Replay {
+            states,
+            actions,
+            ply: 0,
+        })
+    }
+
+    pub fn ply(&self) -> usize {
+        self.ply
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn state(&self) -> &GameState {
+        &self.states[self.ply]
+    }
+
+    /// Moves on one ply, returning what the action did.
+    pub fn step_forward(&mut self) -> Option<ActionResult> {
+        if self.ply >= self.actions.len() {
+            return None;
+        }
+
+        let mut scratch = self.states[self.ply].clone();
+        let result = scratch.apply(self.actions[self.ply]);
+        self.ply += 1;
+        Some(result)
+    }
+
+    /// Moves back one ply, returning what the undone action had done.
+    pub fn step_back(&mut self) -> Option<ActionResult> {
+        if self.ply == 0 {
+            return None;
+        }
+
+        self.ply -= 1;
+        let mut scratch = self.states[self.ply].clone();
+        Some(scratch.apply(self.actions[self.ply]))
+    }
+}
+
+
+pub fn load_replay<P: AsRef<Path>>(
+    path: P,
+    config: &Config,
+) -> Result<Replay, String> {
+    let game = pgn::load_pgn(path, config)?;
+    Replay::new(config, &game)
+}
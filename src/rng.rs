@@ -0,0 +1,103 @@
+use std::fmt;
+
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+
+
+/// The largest seed a game can have. Saves are TOML, whose integers are
+/// signed, so seeds have to fit in an `i64`.
+pub const MAX_SEED: u64 = i64::MAX as u64;
+
+/// Reads a seed someone has typed in, if it's one a game could be saved with.
+pub fn parse_seed(text: &str) -> Option<u64> {
+    match text.trim().parse::<u64>() {
+        Ok(seed) if seed <= MAX_SEED => Some(seed),
+        _ => None,
+    }
+}
+
+/// The game's one source of randomness.
+///
+/// Everything random - restocking the shop, the AI rolling for a purchase or
+/// a blunder - comes from here, so two games started from the same seed and
+/// fed the same human actions play out the same. It counts what it hands
+/// out, so a saved game can pick up exactly where it left off.
+///
+/// The AI's rolls come from a `fork`, so whether it buys has nothing to do
+/// with what the shop restocks with next.
+#[derive(Clone)]
+pub struct GameRng {
+    seed: u64,
+    draws: u64,
+    rng: XorShiftRng,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        let (low, high) = (seed as u32, (seed >> 32) as u32);
+
+        // XorShift can't be seeded with all zeroes, so mix in some constants
+        let rng = XorShiftRng::from_seed([
+            low,
+            high,
+            low ^ 0x9e37_79b9,
+            high ^ 0x7f4a_7c15,
+        ]);
+
+        GameRng {
+            seed,
+            draws: 0,
+            rng,
+        }
+    }
+
+    /// A generator with a seed picked at random. Seeds are kept short enough
+    /// to type back in.
+    pub fn random() -> Self {
+        GameRng::new(u64::from(rand::thread_rng().gen::<u32>()))
+    }
+
+    /// The generator seeded with `seed` after `draws` draws.
+    pub fn resume(seed: u64, draws: u64) -> Self {
+        let mut rng = GameRng::new(seed);
+        for _ in 0..draws {
+            rng.next_u32();
+        }
+        rng
+    }
+
+    /// A generator of its own for rolls that mustn't use up this one's draws.
+    /// It depends only on the seed, the draws so far and `salt`, so it's just
+    /// as repeatable.
+    pub fn fork(&self, salt: u64) -> GameRng {
+        GameRng::new(mix(mix(self.seed ^ mix(self.draws)) ^ salt))
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn draws(&self) -> u64 {
+        self.draws
+    }
+}
+
+impl Rng for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        self.draws += 1;
+        self.rng.next_u32()
+    }
+}
+
+/// Scrambles the bits of `x` (the SplitMix64 finaliser), so that nearby
+/// inputs give unrelated seeds.
+fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl fmt::Debug for GameRng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GameRng({}, {})", self.seed, self.draws)
+    }
+}
@@ -0,0 +1,367 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use toml;
+
+use board::SyncedBoard;
+use chess;
+use clock::Clock;
+use config::Config;
+use data::*;
+use game::{self, GameState};
+use rng::GameRng;
+
+
+pub const SAVE_VERSION: u32 = 1;
+
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SaveGame {
+    pub version: u32,
+    pub whos_turn: ChessColor,
+    pub en_passant: Option<String>,
+    pub halfmove_clock: u32,
+    pub fullmove_number: u32,
+    pub white_coins: u32,
+    pub black_coins: u32,
+    pub start_fen: String,
+    pub positions: Vec<String>,
+    pub repetition_start: usize,
+    pub resigned: Option<ChessColor>,
+    pub out_of_time: Option<ChessColor>,
+    pub draw_agreed: bool,
+    pub seed: u64,
+    pub draws: u64,
+    pub players: Players,
+    pub difficulty: Difficulty,
+    pub rules: RuleSet,
+    pub clock: Option<Clock>,
+    pub offer: Option<PendingOffer>,
+    pub pieces: Vec<SavedPiece>,
+    pub shop: Vec<SavedShopSlot>,
+    pub start_shop: Vec<SavedShopSlot>,
+    pub history: Vec<SavedLogEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedPiece {
+    pub square: String,
+    pub color: ChessColor,
+    pub piece_type: PieceType,
+    pub moved: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedShopSlot {
+    pub piece: Option<PieceForSale>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedLogEntry {
+    pub action: String,
+    pub color: ChessColor,
+    pub piece_type: PieceType,
+    pub coins: u32,
+    pub san: Option<String>,
+    pub shop: Vec<SavedShopSlot>,
+}
+
+
+impl SaveGame {
+    pub fn from_state(state: &GameState) -> Self {
+        let pieces = state
+            .pieces
+            .iter()
+            .map(|piece| SavedPiece {
+                square: chess::square_name(piece.position),
+                color: piece.color,
+                piece_type: piece.piece_type,
+                moved: piece.moved,
+            })
+            .collect();
+
+        let shop = state
+            .pieces_for_sale
+            .iter()
+            .map(|&piece| SavedShopSlot { piece })
+            .collect();
+
+        SaveGame {
+            version: SAVE_VERSION,
+            whos_turn: state.whos_turn,
+            en_passant: state.move_state.en_passant.map(chess::square_name),
+            halfmove_clock: state.move_state.halfmove_clock,
+            fullmove_number: state.move_state.fullmove_number,
+            white_coins: state.white_coins,
+            black_coins: state.black_coins,
+            start_fen: state.start_fen.clone(),
+            positions: state.board.positions().to_vec(),
+            repetition_start: state.board.repetition_start(),
+            resigned: match state.game_outcome {
+                GameOutcome::Resigned(color) => Some(color),
+                _ => None,
+            },
+            out_of_time: match state.game_outcome {
+                GameOutcome::OutOfTime(color) => Some(color),
+                _ => None,
+            },
+            draw_agreed: state.game_outcome == GameOutcome::AgreedDraw,
+            seed: state.rng.seed(),
+            draws: state.rng.draws(),
+            players: state.players,
+            difficulty: state.difficulty.clone(),
+            rules: state.rules.clone(),
+            clock: state.clock.clone(),
+            offer: state.offer,
+            pieces,
+            shop,
+            start_shop: state
+                .start_shop
+                .iter()
+                .map(|&piece| SavedShopSlot { piece })
+                .collect(),
+            history: state
+                .history
+                .iter()
+                .map(|entry| SavedLogEntry {
+                    action: chess::format_action(entry.action),
+                    color: entry.color,
+                    piece_type: entry.piece_type,
+                    coins: entry.coins,
+                    san: entry.san.clone(),
+                    shop: entry
+                        .shop
+                        .iter()
+                        .map(|&piece| SavedShopSlot { piece })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn into_state(self, config: &Config) -> Result<GameState, String> {
+        if self.version != SAVE_VERSION {
+            return Err(format!("Unsupported save version {}", self.version));
+        }
+
+        let mut pieces = Vec::with_capacity(self.pieces.len());
+        for saved in &self.pieces {
+            let position = chess::parse_square(&saved.square)
+                .ok_or_else(|| format!("Invalid square '{}'", saved.square))?;
+            let mut piece =
+                game::new_piece(position, saved.color, saved.piece_type);
+            piece.moved = saved.moved;
+            pieces.push(piece);
+        }
+
+        let en_passant = match self.en_passant {
+            Some(ref square) => Some(
+                chess::parse_square(square)
+                    .ok_or_else(|| format!("Invalid square '{}'", square))?,
+            ),
+            None => None,
+        };
+
+        let mut history = Vec::with_capacity(self.history.len());
+        for saved in &self.history {
+            let action = chess::parse_action(&saved.action)
+                .ok_or_else(|| format!("Invalid action '{}'", saved.action))?;
+            history.push(LogEntry {
+                action,
+                color: saved.color,
+                piece_type: saved.piece_type,
+                coins: saved.coins,
+                san: saved.san.clone(),
+                shop: saved.shop.iter().map(|slot| slot.piece).collect(),
+            });
+        }
+
+        let mut state = GameState::new(config);
+
+        if self.shop.len() != game::SHOP_SIZE
+            || self.start_shop.len() != game::SHOP_SIZE
+        {
+            return Err(format!("Expected {} shop slots", game::SHOP_SIZE));
+        }
+        for (slot, saved) in state.pieces_for_sale.iter_mut().zip(&self.shop) {
+            *slot = saved.piece;
+        }
+        for (slot, saved) in state.start_shop.iter_mut().zip(&self.start_shop) {
+            *slot = saved.piece;
+        }
+
+        state.pieces = pieces;
+        state.whos_turn = self.whos_turn;
+        state.move_state = MoveState {
+            en_passant,
+            halfmove_clock: self.halfmove_clock,
+            fullmove_number: self.fullmove_number,
+        };
+        state.white_coins = self.white_coins;
+        state.black_coins = self.black_coins;
+        state.players = self.players;
+        state.difficulty = self.difficulty;
+        state.rules = self.rules;
+        state.clock = self.clock;
+        state.offer = self.offer;
+        state.start_fen = self.start_fen;
+        state.history = history;
+        state.rng = GameRng::resume(self.seed, self.draws);
+        state.board = SyncedBoard::with_history(
+            &state.fen(),
+            self.positions,
+            self.repetition_start,
+        )?;
+        // Resigning, running out of time and agreeing a draw can't be worked
+        // out from the position
+        state.game_outcome = match (self.resigned, self.out_of_time) {
+            (Some(color), _) => GameOutcome::Resigned(color),
+            (None, Some(color)) => GameOutcome::OutOfTime(color),
+            (None, None) if self.draw_agreed => GameOutcome::AgreedDraw,
+            (None, None) => state.detect_outcome(),
+        };
+
+        Ok(state)
+    }
+}
+
+
+pub fn save_game<P: AsRef<Path>>(
+    path: P,
+    state: &GameState,
+) -> Result<(), String> {
+    let text = save_to_string(state)?;
+
+    let mut file = File::create(path).map_err(|err| err.to_string())?;
+    file.write_all(text.as_bytes())
+        .map_err(|err| err.to_string())
+}
+
+pub fn load_game<P: AsRef<Path>>(
+    path: P,
+    config: &Config,
+) -> Result<GameState, String> {
+    let mut file = File::open(path).map_err(|err| err.to_string())?;
+    let mut buffer = String::new();
+    file.read_to_string(&mut buffer)
+        .map_err(|err| err.to_string())?;
+
+    load_from_str(&buffer, config)
+}
+
+pub fn save_to_string(state: &GameState) -> Result<String, String> {
+    let save = SaveGame::from_state(state);
+    toml::to_string(&save).map_err(|err| err.to_string())
+}
+
+pub fn load_from_str(text: &str, config: &Config) -> Result<GameState, String> {
+    let save: SaveGame = toml::from_str(text).map_err(|err| err.to_string())?;
+    save.into_state(config)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::CONFIG;
+    use okmath::*;
+
+    fn square(name: &str) -> Vec2<i32> {
+        chess::parse_square(name).unwrap()
+    }
+
+    fn move_action(from: &str, to: &str) -> Action {
+        Action::Move {
+            from: square(from),
+            to: square(to),
+            promotion: None,
+        }
+    }
+
+    fn reload(state: &GameState) -> GameState {
+        let text = save_to_string(state).unwrap();
+        load_from_str(&text, &CONFIG).unwrap()
+    }
+
+    #[test]
+    fn a_game_in_progress_survives_saving() {
+        let mut state = GameState::new_seeded(&CONFIG, 7);
+        let control = game::time_control_preset(&CONFIG, "blitz").unwrap();
+        state.clock = control.map(Clock::new);
+        state.white_coins = 20;
+        state.pieces_for_sale[0] = Some(PieceForSale {
+            piece_type: PieceType::Knight,
+            discounted: true,
+        });
+
+        state.apply(Action::Buy {
+            slot: 0,
+            place: square("b1"),
+        });
+        state.tick_clock(2.5);
+        state.apply(move_action("e2", "e4"));
+        state.tick_clock(1.25);
+        state.apply(Action::Sell(square("a7")));
+        state.apply(move_action("e7", "e5"));
+        state.apply(move_action("b1", "c3"));
+        state.pieces_for_sale[2] = Some(PieceForSale {
+            piece_type: PieceType::Bishop,
+            discounted: true,
+        });
+        assert_eq!(state.history.len(), 5);
+
+        let loaded = reload(&state);
+
+        assert_eq!(loaded.fen(), state.fen());
+        assert_eq!(loaded.move_state, state.move_state);
+        assert_eq!(loaded.white_coins, state.white_coins);
+        assert_eq!(loaded.black_coins, state.black_coins);
+        assert_eq!(loaded.pieces_for_sale, state.pieces_for_sale);
+        assert_eq!(loaded.start_shop, state.start_shop);
+        assert_eq!(loaded.history, state.history);
+        assert_eq!(loaded.rng.seed(), 7);
+        assert_eq!(loaded.rng.draws(), state.rng.draws());
+        assert_eq!(loaded.clock, state.clock);
+        assert_eq!(loaded.board.positions(), state.board.positions());
+        assert_eq!(
+            loaded.board.repetition_start(),
+            state.board.repetition_start()
+        );
+        assert_eq!(loaded.game_outcome, GameOutcome::Ongoing);
+
+        let moved = |state: &GameState| {
+            state
+                .pieces
+                .iter()
+                .map(|piece| (chess::square_name(piece.position), piece.moved))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(moved(&loaded), moved(&state));
+    }
+
+    #[test]
+    fn outcomes_the_position_cant_show_are_kept() {
+        let outcomes = [
+            GameOutcome::Resigned(ChessColor::Black),
+            GameOutcome::OutOfTime(ChessColor::White),
+            GameOutcome::AgreedDraw,
+        ];
+
+        for &outcome in &outcomes {
+            let mut state = GameState::new_seeded(&CONFIG, 7);
+            state.game_outcome = outcome;
+
+            assert_eq!(reload(&state).game_outcome, outcome);
+        }
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let state = GameState::new_seeded(&CONFIG, 7);
+        let mut save = SaveGame::from_state(&state);
+        save.version = SAVE_VERSION + 1;
+
+        assert!(save.into_state(&CONFIG).is_err());
+    }
+}
@@ -1,6 +1,114 @@
 use okmath::*;
 use glium_text::{FontTexture, TextDisplay, TextSystem};
 
+use chessjam::data::*;
+use chessjam::game::GameState;
+
+
+#[derive(Debug, Copy, Clone)]
+pub enum ControlState {
+    Idle,
+    SelectedPieceIndex(usize),
+    SelectedPurchaseIndex(usize),
+    PendingPromotion { from: Vec2<i32>, to: Vec2<i32> },
+}
+
+
+/// The tiles on which the promotion picker shows its choices, running back
+/// down the file from the promotion square.
+pub fn promotion_choices(to: Vec2<i32>) -> [(Vec2<i32>, PieceType); 4] {
+    let (x, y) = to.as_tuple();
+    let step = if y == 0 { 1 } else { -1 };
+
+    [
+        (vec2(x, y), PieceType::Queen),
+        (vec2(x, y + step), PieceType::Rook),
+        (vec2(x, y + step * 2), PieceType::Bishop),
+        (vec2(x, y + step * 3), PieceType::Knight),
+    ]
+}
+
+
+/// What a player can do besides playing, from buttons under their side's coin
+/// counter.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GameButton {
+    Resign,
+    OfferDraw,
+    TakeBack,
+    Accept,
+    Decline,
+}
+
+impl GameButton {
+    pub fn label(self) -> &'static str {
+        match self {
+            GameButton::Resign => "Resign",
+            GameButton::OfferDraw => "Offer draw",
+            GameButton::TakeBack => "Take back",
+            GameButton::Accept => "Accept",
+            GameButton::Decline => "Decline",
+        }
+    }
+
+    pub fn action(self, color: ChessColor) -> Action {
+        match self {
+            GameButton::Resign => Action::Resign(color),
+            GameButton::OfferDraw => Action::Offer(color, Offer::Draw),
+            GameButton::TakeBack => Action::Offer(color, Offer::TakeBack),
+            GameButton::Accept => Action::Answer(color, true),
+            GameButton::Decline => Action::Answer(color, false),
+        }
+    }
+}
+
+const BUTTON_WIDTH: f32 = 2.4;
+const BUTTON_HEIGHT: f32 = 0.4;
+
+/// The buttons `color` can press right now, and where their labels go. With
+/// an offer to answer, answering it is all there is to do besides resigning.
+pub fn game_buttons(
+    state: &GameState,
+    color: ChessColor,
+) -> Vec<(GameButton, Vec2<f32>)> {
+    use self::GameButton::*;
+
+    let has_acted = state.history.iter().any(|entry| entry.color == color);
+    let buttons = match state.offer {
+        Some(pending) if pending.from != color => vec![Accept, Decline, Resign],
+        Some(_) => vec![Resign],
+        None if has_acted => vec![Resign, OfferDraw, TakeBack],
+        None => vec![Resign, OfferDraw],
+    };
+
+    let x = match color {
+        ChessColor::White => -7.2,
+        ChessColor::Black => 4.8,
+    };
+
+    buttons
+        .into_iter()
+        .enumerate()
+        .map(|(row, button)| (button, vec2(x, 1.6 - 0.5 * row as f32)))
+        .collect()
+}
+
+/// The button under `cursor`, which is in the same units as the labels.
+pub fn button_at(
+    buttons: &[(ChessColor, GameButton, Vec2<f32>)],
+    cursor: Vec2<f32>,
+) -> Option<(ChessColor, GameButton)> {
+    let (cx, cy) = cursor.as_tuple();
+
+    buttons
+        .iter()
+        .find(|&&(_, _, pos)| {
+            let (x, y) = pos.as_tuple();
+            cx >= x && cx <= x + BUTTON_WIDTH && cy >= y && cy <= y + BUTTON_HEIGHT
+        })
+        .map(|&(color, button, _)| (color, button))
+}
+
 
 pub struct LabelRenderer<'a> {
     labels: Vec<(TextDisplay<&'a FontTexture>, Vec3<f32>, f32)>,
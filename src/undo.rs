@@ -0,0 +1,266 @@
+use std::mem;
+
+use data::*;
+use game::{ActionResult, GameState};
+
+
+/// Takes actions back and puts them back again.
+///
+/// Like `Replay`, it keeps the full `GameState` from before each action
+/// instead of working out how to undo it, so wallets, the shop and anything
+/// restocked come back exactly as they were. What an action did is found by
+/// re-applying it to a scratch copy, for animation.
+///
+/// The clocks come back too, so callers that don't want time handed back
+/// should hold on to `clock` themselves.
+///
+/// Resigning and offers aren't taken back, and a game that has ended with a
+/// resignation, an agreed draw or a flag stays over: nothing can be undone.
+#[derive(Default)]
+pub struct UndoHistory {
+    done: Vec<(GameState, Action)>,
+    undone: Vec<(GameState, Action)>,
+}
+
+impl UndoHistory {
+    pub fn new() -> Self {
+        UndoHistory::default()
+    }
+
+    /// Notes an action that has just been taken from `before`. Anything that
+    /// had been undone can't be redone after that.
+    pub fn record(&mut self, before: GameState, action: Action) {
+        match action {
+            Action::Resign(_) | Action::Offer(..) | Action::Answer(..) => (),
+            _ => self.done.push((before, action)),
+        }
+        self.undone.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.done.clear();
+        self.undone.clear();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.done.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    /// Puts `state` back to before the last action, returning the state it
+    /// was in and what the undone action had done.
+    pub fn undo(
+        &mut self,
+        state: &mut GameState,
+    ) -> Option<(GameState, ActionResult)> {
+        if is_settled(state) {
+            return None;
+        }
+
+        let (before, action) = self.done.pop()?;
+        let result = before.clone().apply(action);
+        let after = mem::replace(state, before);
+
+        self.undone.push((after.clone(), action));
+        Some((after, result))
+    }
+
+    /// Takes the last undone action again, returning what it did.
+    pub fn redo(&mut self, state: &mut GameState) -> Option<ActionResult> {
+        if is_settled(state) {
+            return None;
+        }
+
+        let (after, action) = self.undone.pop()?;
+        let result = state.clone().apply(action);
+        let before = mem::replace(state, after);
+
+        self.done.push((before, action));
+        Some(result)
+    }
+
+    /// Undoes actions until it's the turn of a side `skip` doesn't pass over,
+    /// so that against the AI the human gets their own last action back.
+    /// Returns the last step, as `undo` does.
+    pub fn undo_turn<F>(
+        &mut self,
+        state: &mut GameState,
+        skip: F,
+    ) -> Option<(GameState, ActionResult)>
+    where
+        F: Fn(ChessColor) -> bool,
+    {
+        let mut last = None;
+
+        while let Some(step) = self.undo(state) {
+            last = Some(step);
+            if !skip(state.whos_turn) {
+                break;
+            }
+        }
+
+        last
+    }
+
+    /// Redoes actions until it's the turn of a side `skip` doesn't pass over.
+    /// Returns what the last one did.
+    pub fn redo_turn<F>(
+        &mut self,
+        state: &mut GameState,
+        skip: F,
+    ) -> Option<ActionResult>
+    where
+        F: Fn(ChessColor) -> bool,
+    {
+        let mut last = None;
+
+        while let Some(result) = self.redo(state) {
+            last = Some(result);
+            if !skip(state.whos_turn) {
+                break;
+            }
+        }
+
+        last
+    }
+}
+
+
+/// Whether the game has ended in a way that taking back moves can't change.
+fn is_settled(state: &GameState) -> bool {
+    match state.game_outcome {
+        GameOutcome::Resigned(_)
+        | GameOutcome::AgreedDraw
+        | GameOutcome::OutOfTime(_) => true,
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chess;
+    use clock::Clock;
+    use config::CONFIG;
+    use game;
+
+    fn play(state: &mut GameState, history: &mut UndoHistory, text: &str) {
+        let action = chess::parse_action(text).unwrap();
+        let before = state.clone();
+        assert!(!state.apply(action).is_empty(), "{} was refused", text);
+        history.record(before, action);
+    }
+
+    #[test]
+    fn wallets_and_the_shop_come_back() {
+        let mut state = GameState::new_seeded(&CONFIG, 5);
+        let mut history = UndoHistory::new();
+
+        play(&mut state, &mut history, "sell a2");
+        let sold = state.clone();
+        play(&mut state, &mut history, "e2e4");
+        let moved = state.clone();
+
+        history.undo(&mut state).unwrap();
+        assert_eq!(state.fen(), sold.fen());
+        assert_eq!(state.white_coins, sold.white_coins);
+        assert_eq!(state.pieces_for_sale, sold.pieces_for_sale);
+        assert_eq!(state.history, sold.history);
+
+        history.undo(&mut state).unwrap();
+        assert_eq!(state.white_coins, 0);
+        assert!(state.history.is_empty());
+        assert!(!history.can_undo());
+
+        history.redo(&mut state).unwrap();
+        history.redo(&mut state).unwrap();
+        assert_eq!(state.fen(), moved.fen());
+        assert_eq!(state.white_coins, moved.white_coins);
+        assert_eq!(state.pieces_for_sale, moved.pieces_for_sale);
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn clocks_come_back_with_the_rest() {
+        let mut state = GameState::new_seeded(&CONFIG, 5);
+        let control = game::time_control_preset(&CONFIG, "blitz").unwrap();
+        state.clock = control.map(Clock::new);
+        let mut history = UndoHistory::new();
+
+        state.tick_clock(4.0);
+        let before = state.clock.clone();
+        play(&mut state, &mut history, "e2e4");
+        state.tick_clock(6.0);
+
+        history.undo(&mut state).unwrap();
+        assert_eq!(state.clock, before);
+    }
+
+    #[test]
+    fn the_ais_replies_can_be_skipped() {
+        let mut state = GameState::new_seeded(&CONFIG, 5);
+        state.players = Players {
+            white: PlayerKind::Human,
+            black: PlayerKind::Ai,
+        };
+        let players = state.players;
+        let skip = |color: ChessColor| players.is_ai(color);
+        let mut history = UndoHistory::new();
+
+        let start = state.fen();
+        play(&mut state, &mut history, "e2e4");
+        play(&mut state, &mut history, "e7e5");
+        let end = state.fen();
+
+        history.undo_turn(&mut state, skip).unwrap();
+        assert_eq!(state.fen(), start);
+        assert!(!history.can_undo());
+
+        history.redo_turn(&mut state, skip).unwrap();
+        assert_eq!(state.fen(), end);
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn offers_are_not_undone() {
+        let mut state = GameState::new_seeded(&CONFIG, 5);
+        let mut history = UndoHistory::new();
+
+        play(&mut state, &mut history, "e2e4");
+        play(&mut state, &mut history, "offer black draw");
+        assert!(state.offer.is_some());
+
+        history.undo(&mut state).unwrap();
+        assert!(state.history.is_empty());
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn resignations_and_agreed_draws_stand() {
+        let endings: [&[&str]; 2] = [
+            &["resign white"],
+            &["offer white draw", "answer black accept"],
+        ];
+
+        for ending in &endings {
+            let mut state = GameState::new_seeded(&CONFIG, 5);
+            let mut history = UndoHistory::new();
+
+            play(&mut state, &mut history, "e2e4");
+            for text in ending.iter() {
+                play(&mut state, &mut history, text);
+            }
+            let outcome = state.game_outcome;
+            assert!(outcome != GameOutcome::Ongoing);
+
+            assert!(history.undo(&mut state).is_none());
+            assert!(history.undo_turn(&mut state, |_| true).is_none());
+            assert_eq!(state.game_outcome, outcome);
+            assert_eq!(state.history.len(), 1);
+        }
+    }
+}